@@ -0,0 +1,90 @@
+//! Detection of the character encoding of HTML code given as bytes.
+
+use crate::tokenizer::{Token, Tokenizer};
+use encoding_rs::{Encoding, WINDOWS_1252, X_USER_DEFINED};
+
+/// How many bytes at the beginning of the code are searched for `<meta>` tags.
+const PRESCAN_LEN: usize = 1024;
+
+/// Find the encoding of the code. It is taken from the byte order mark, then from
+/// `<meta charset>` or `<meta http-equiv="Content-Type">` tags. Otherwise default encoding
+/// is used. Returns the encoding and the length of the byte order mark.
+pub fn detect(bytes: &[u8], default: &'static Encoding) -> (&'static Encoding, usize) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        return (encoding, bom_len);
+    }
+
+    match prescan(&bytes[..std::cmp::min(bytes.len(), PRESCAN_LEN)]) {
+        Some(encoding) => (encoding, 0),
+        None => (default, 0),
+    }
+}
+
+/// Search for the encoding declared in `<meta>` tags.
+fn prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    // Tags are always written in ASCII, other characters do not matter.
+    let html = String::from_utf8_lossy(bytes);
+    let mut tokenizer = Tokenizer::new(&html);
+    while let Some((token, _)) = tokenizer.next_token() {
+        let (name, attrs) = match token {
+            Token::StartTag { name, attrs, .. } => (name, attrs),
+            _ => continue,
+        };
+        if !name.eq_ignore_ascii_case("meta") {
+            continue;
+        }
+
+        let value = |key: &str| {
+            attrs.iter()
+                .find(|attr| attr.name.eq_ignore_ascii_case(key))
+                .and_then(|attr| attr.value.as_deref())
+        };
+        let label = if let Some(charset) = value("charset") {
+            Some(charset)
+        } else if value("http-equiv").map_or(false, |s| s.eq_ignore_ascii_case("content-type")) {
+            value("content").and_then(charset_from_content)
+        } else {
+            None
+        };
+
+        if let Some(encoding) = label.and_then(|s| Encoding::for_label(s.trim().as_bytes())) {
+            return if encoding == X_USER_DEFINED {
+                // The standard reads the code as windows-1252 when this encoding is declared.
+                Some(WINDOWS_1252)
+            } else {
+                // Code that is read as ASCII cannot be in UTF-16 whatever the tag says, so it
+                // is read as UTF-8 then.
+                Some(encoding.output_encoding())
+            };
+        }
+    }
+    None
+}
+
+/// Get the charset from the value of `content` attribute like `text/html; charset=utf-8`.
+fn charset_from_content(content: &str) -> Option<&str> {
+    let lower = content.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("charset") {
+        let start = from + i + "charset".len();
+        let rest = content[start..].trim_start();
+        if !rest.starts_with('=') {
+            from = start;
+            continue;
+        }
+
+        let rest = rest[1..].trim_start();
+        return match rest.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => {
+                rest[1..].find(quote).map(|end| &rest[1..end + 1])
+            },
+            Some(_) => {
+                let end = rest.find(|c: char| c == ';' || c.is_ascii_whitespace())
+                    .unwrap_or(rest.len());
+                Some(&rest[..end])
+            },
+            None => None,
+        };
+    }
+    None
+}
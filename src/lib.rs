@@ -45,18 +45,91 @@
 //! assert_eq!(" more text", last_text.text().unwrap());
 //! ```
 
-extern crate quick_xml;
 extern crate memchr;
+extern crate encoding_rs;
 
-use quick_xml::events::{Event, BytesEnd, BytesText, BytesStart};
-use quick_xml::{Error, Reader};
+mod charset;
+mod entities;
+mod tokenizer;
+
+use tokenizer::{Token, TokenAttribute, Tokenizer, is_raw_text_element};
 use std::collections::LinkedList;
+use std::io::BufRead;
 use memchr::{memchr_iter};
 use std::sync::{Arc};
 use std::ops::{Deref, DerefMut};
+use std::borrow::Cow;
+use std::ops::Range;
+
+pub use encoding_rs::Encoding;
 
 type SharedNode = Arc<Node>;
 
+/// Elements that are processed in a special way when building the tree. Their closing tags
+/// cannot close any other element that is opened inside of them.
+const SPECIAL_ELEMENTS: &[&str] = &[
+    "address", "applet", "area", "article", "aside", "base", "basefont", "bgsound", "blockquote",
+    "body", "br", "button", "caption", "center", "col", "colgroup", "dd", "details", "dir", "div",
+    "dl", "dt", "embed", "fieldset", "figcaption", "figure", "footer", "form", "frame",
+    "frameset", "h1", "h2", "h3", "h4", "h5", "h6", "head", "header", "hgroup", "hr", "html",
+    "iframe", "img", "input", "keygen", "li", "link", "listing", "main", "marquee", "menu",
+    "meta", "nav", "noembed", "noframes", "noscript", "object", "ol", "p", "param", "plaintext",
+    "pre", "script", "search", "section", "select", "source", "style", "summary", "table",
+    "tbody", "td", "template", "textarea", "tfoot", "th", "thead", "title", "tr", "track", "ul",
+    "wbr", "xmp",
+];
+
+/// Elements which opening tag closes the paragraph that is still open.
+const CLOSE_P_ELEMENTS: &[&str] = &[
+    "address", "article", "aside", "blockquote", "center", "details", "dialog", "dir", "div",
+    "dl", "fieldset", "figcaption", "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5",
+    "h6", "header", "hgroup", "hr", "listing", "main", "menu", "nav", "ol", "p", "plaintext",
+    "pre", "search", "section", "summary", "table", "ul", "xmp",
+];
+
+/// Elements which closing tags can be omitted. Such elements get closed when their parent closes.
+const IMPLIED_END_ELEMENTS: &[&str] = &[
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+/// Other elements which closing tags can be omitted. Parser does not report them as unclosed.
+const OPTIONAL_END_ELEMENTS: &[&str] = &[
+    "body", "caption", "colgroup", "head", "html", "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+/// Elements that limit the scope where the parser searches for open elements to close.
+const SCOPE_ELEMENTS: &[&str] = &[
+    "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template",
+];
+
+const HEADING_ELEMENTS: &[&str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+const TABLE_CELL_ELEMENTS: &[&str] = &["td", "th"];
+
+const TABLE_SECTION_ELEMENTS: &[&str] = &["tbody", "tfoot", "thead"];
+
+const TABLE_ELEMENTS: &[&str] = &[
+    "caption", "colgroup", "table", "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+/// Elements which content keeps all its whitespace. Single line break right after their opening
+/// tag is ignored.
+const PREFORMATTED_ELEMENTS: &[&str] = &["listing", "pre", "textarea"];
+
+/// Elements which content is not HTML but SVG or MathML.
+const FOREIGN_ELEMENTS: &[&str] = &["math", "svg"];
+
+/// SVG and MathML elements which content is HTML again.
+const HTML_INTEGRATION_POINTS: &[&str] = &[
+    "desc", "foreignobject", "mi", "mn", "mo", "ms", "mtext", "title",
+];
+
+/// Elements that never have any content nor closing tag.
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
 /// Children of the node. All tags that are inside of parent node are listed in this struct.
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct Children(Vec<NodeAccess>);
@@ -75,11 +148,65 @@ pub enum ChildrenType {
     Sharable,
 }
 
+/// How whitespace in the texts between tags is handled. Content of raw text elements like
+/// `<script>` and of preformatted elements like `<pre>` is never changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Whitespace {
+    /// Trim the whitespace that only formats the code: whitespace at the start of the text
+    /// that begins with a line break, whitespace at the end of the text that has a line break
+    /// and the texts that consist of whitespace only.
+    Trim,
+
+    /// Keep every text exactly as it is in the code, including the texts that consist of
+    /// whitespace only, e.g. line breaks and indentation between tags.
+    Preserve,
+
+    /// Drop the texts that consist of whitespace only. Other texts are kept exactly as they
+    /// are, with all their leading and trailing whitespace.
+    DropEmpty,
+
+    /// Replace every run of whitespace with single space. Texts that consist of whitespace
+    /// only become a single space, so the space between inline elements like
+    /// `<b>a</b> <i>b</i>` is kept.
+    Collapse,
+}
+
+/// How parser reacts on the problems in the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Fail on the first problem. Nothing is parsed after it. Elements that are still open at
+    /// the end of the code are closed without error, and repeated attributes are only
+    /// reported unless [`DuplicateAttributes::Error`] is used.
+    Strict,
+
+    /// Recover from the problems the way browsers do and report them as diagnostics.
+    Recovering,
+}
+
+/// Which attribute is kept when the tag has several attributes with the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateAttributes {
+    /// Keep the first attribute and drop the others the way browsers do.
+    FirstWins,
+
+    /// Keep the last attribute and drop the others.
+    LastWins,
+
+    /// Keep all the attributes. Only the first one is found by name then.
+    KeepAll,
+
+    /// Fail on the duplicate attribute even in recovering mode.
+    Error,
+}
+
 /// Contains information about opening and corresponding closing tags. It also can
 /// contain the value of the text between opening and closing tags if there are no children.
 /// Otherwise, if there are children mixed with text then each text chunk is separated in
 /// it's own node with other children in order they appear in the code.
-#[derive(Clone, Debug, PartialEq, Default)]
+///
+/// Nodes are equal if they have the same content. Their positions in the code and the code
+/// that is kept in lossless mode are not compared.
+#[derive(Clone, Debug, Default)]
 pub struct Node {
     /// Start of the tag if any. It may be empty if this is a trailing text at the beginning of
     /// the HTML code. It also is empty in root node.
@@ -88,26 +215,231 @@ pub struct Node {
     /// Text value if there is a text between opening and closing tags.
     text: Option<String>,
 
+    /// Text as it was written in the HTML code, with character references not decoded.
+    raw_text: Option<String>,
+
+    /// Whether character references in the text are left as they were in the code because
+    /// decoding them was turned off.
+    undecoded: bool,
+
     /// Closing tag if any.
     end: Option<String>,
 
     /// Direct children of this node. Does not include children of children nodes.
     children: Children,
+
+    /// Content of the node if it is neither a tag nor a text, e.g. comment.
+    markup: Option<Markup>,
+
+    /// Part of the code with the whole node, including opening and closing tags.
+    span: Option<Span>,
+
+    /// Part of the code with the closing tag.
+    end_span: Option<Span>,
+
+    /// Code of the closing tag as it was written. Only kept in lossless mode.
+    raw_end: Option<String>,
+
+    /// Code of the markup as it was written. Only kept in lossless mode.
+    raw_markup: Option<String>,
+
+    /// Code before the node which gives no node, like skipped comments or closing tags
+    /// without opened element. Only kept in lossless mode.
+    raw_before: Option<String>,
 }
 
-/// Information carried in the opening tag.
+/// Content of the node that is neither a tag nor a text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Markup {
+    /// Comment with the text between `<!--` and `-->`.
+    Comment(String),
+
+    /// Document type declaration with the text between `<!DOCTYPE` and `>`.
+    Doctype(String),
+
+    /// XML declaration with the text between `<?` and `?>`, e.g. `xml version="1.0"`.
+    Declaration(String),
+
+    /// Processing instruction with the text between `<?` and `?>`.
+    Instruction(String),
+
+    /// CDATA section with the text between `<![CDATA[` and `]]>`.
+    CData(String),
+}
+
+/// Part of the code read by [`Events`]. Texts are already trimmed and decoded the same way they
+/// are when building the node tree.
 #[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Opening tag with parsed attributes. Namespaces are resolved only when building the tree
+    /// so the tag is always in HTML namespace here.
+    StartTag(OpeningTag),
+
+    /// Closing tag with its name.
+    EndTag(String),
+
+    /// Text between tags.
+    Text {
+        /// Text decoded according to the settings.
+        text: String,
+
+        /// Text as it is written in the code.
+        raw_text: String,
+    },
+
+    /// Comment, DOCTYPE or other markup.
+    Markup(Markup),
+}
+
+/// Iterator over the events of the code. It is a lightweight alternative to the node tree
+/// when only some tags or attributes are needed.
+///
+/// The events follow the code as it is. Elements which closing tags are omitted do not get
+/// any closing event, nor are unmatched closing tags dropped. In strict mode the first problem
+/// in the code is returned as an error and the iteration stops. In recovering mode the problems
+/// are collected and can be taken with [`Events::take_diagnostics`].
+///
+/// # Examples
+/// ```
+/// # use htmldom_read::{Event, Events};
+/// let html = r#"<a href="/a">A</a><p>Text<a href="/b">B</a>"#;
+/// let settings = Default::default();
+/// let links: Vec<_> = Events::new(html, &settings)
+///     .filter_map(|event| match event.unwrap().0 {
+///         Event::StartTag(tag) => tag.attribute_by_name("href").map(|a| a.value().to_owned()),
+///         _ => None,
+///     })
+///     .collect();
+/// assert_eq!(vec!["/a", "/b"], links);
+/// ```
+pub struct Events<'a> {
+    tokenizer: Tokenizer<'a>,
+    settings: &'a LoadSettings,
+
+    /// How many preformatted elements like `<pre>` are open.
+    preformatted: usize,
+
+    /// Whether the previous token opened preformatted element.
+    skip_newline: bool,
+
+    /// How many foreign elements like `<svg>` are open.
+    foreign: usize,
+
+    /// Whether an error was returned in strict mode.
+    failed: bool,
+
+    /// Problems in the code that were recovered from and not taken yet.
+    diagnostics: Vec<Error>,
+
+    /// Code of the token of the last event. Only kept in lossless mode.
+    source: String,
+
+    /// Code that was read but gave no event, like skipped comments. Only kept in lossless mode.
+    dropped: String,
+}
+
+/// Namespace of the element or attribute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Namespace {
+    Html,
+    Svg,
+    MathMl,
+    XLink,
+    Xml,
+    Xmlns,
+
+    /// Any other namespace declared with `xmlns` attribute. Contains its URI.
+    Other(String),
+}
+
+/// Information carried in the opening tag.
+#[derive(Clone, Debug)]
 pub struct OpeningTag {
     empty: bool, // Whether this tag is self-closing.
     name: String,
     attrs: Vec<Attribute>,
+    span: Option<Span>,
+    namespace: Namespace,
+
+    /// Code of the tag before the attributes and after them. Only kept in lossless mode.
+    raw_start: Option<String>,
+    raw_end: Option<String>,
+
+    /// Code of the repeated attributes that were dropped, each with the index of the
+    /// attribute it is written before. Only kept in lossless mode.
+    raw_dropped: Vec<(usize, String)>,
 }
 
 /// Attribute of the tag.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct Attribute {
     name: String,
+
+    /// Value exactly as it was given, only the character references are decoded. None for
+    /// attributes without value like `disabled` in `<input disabled>`.
+    value: Option<String>,
+
+    /// Parts of the value separated with whitespaces.
     values: Vec<String>,
+
+    /// Namespace of the prefixed attribute of foreign element like `xlink:href`.
+    namespace: Option<Namespace>,
+
+    /// Code of the attribute with the whitespace before it. Only kept in lossless mode.
+    raw: Option<String>,
+}
+
+/// Error that occurred while parsing HTML code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+    position: Position,
+}
+
+/// The reason why parser could not continue.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// Code ended in the middle of some construct. Contains the name of this construct.
+    UnexpectedEof(String),
+
+    /// Found token that cannot appear in this place.
+    UnexpectedToken(String),
+
+    /// Markup that starts with `<!` but is not a comment, CDATA or DOCTYPE.
+    UnexpectedBang,
+
+    /// Closing tag that has no corresponding opened element. Contains the tag name.
+    UnmatchedEndTag(String),
+
+    /// Element which closing tag is missing while it cannot be omitted. Contains the tag name.
+    UnclosedElement(String),
+
+    /// Several attributes with the same name in one tag. Contains the attribute name and
+    /// the policy that was applied.
+    DuplicateAttribute(String, DuplicateAttributes),
+
+    /// Any other error reported by the reader. Contains the description of the error.
+    Other(String),
+}
+
+/// Location in the HTML code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Position {
+    /// Offset in bytes from the beginning of the code.
+    offset: usize,
+
+    /// Line number starting from 1.
+    line: usize,
+
+    /// Column number in characters starting from 1.
+    column: usize,
+}
+
+/// Part of the HTML code between two positions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Span {
+    start: Position,
+    end: Position,
 }
 
 /// Settings that provide different options of how to parse HTML.
@@ -116,6 +448,14 @@ pub struct LoadSettings {
 
     all_text_separately: bool,
     children_type: ChildrenType,
+    skip_comments: bool,
+    decode_entities: bool,
+    mode: ParseMode,
+    whitespace: Whitespace,
+    keep_foreign_case: bool,
+    default_encoding: &'static Encoding,
+    lossless: bool,
+    duplicate_attributes: DuplicateAttributes,
 }
 
 /// Settings to fetch children nodes that apply to given criteria.
@@ -171,6 +511,12 @@ pub struct ChildrenFetch<'a> {
     /// If exact value is not set then this defines a part of the value separated with whitespaces
     /// to be found.
     value_part: Option<&'a str>,
+
+    /// Text of the comment to search for.
+    comment: Option<&'a str>,
+
+    /// Namespace of the elements to search for.
+    namespace: Option<&'a Namespace>,
 }
 
 /// Mutable `ChildrenFetch`. Allows to get mutable access to returned nodes.
@@ -179,6 +525,56 @@ pub struct ChildrenFetchMut<'a> {
     inner: ChildrenFetch<'a>,
 }
 
+/// Parser that is fed by the parts of the code as they arrive, e.g. from the socket.
+///
+/// # Examples
+/// ```
+/// # use htmldom_read::Parser;
+/// let settings = Default::default();
+/// let mut parser = Parser::new(&settings);
+/// parser.feed(b"<p>Caf\xC3").unwrap();
+/// parser.feed(b"\xA9 &am").unwrap();
+/// parser.feed(b"p; co</p>").unwrap();
+/// let root = parser.finish().unwrap().unwrap();
+/// assert_eq!("Caf\u{E9} & co", root.children()[0].children()[0].text().unwrap());
+/// ```
+pub struct Parser<'a> {
+    events: Events<'a>,
+    builder: TreeBuilder<'a>,
+}
+
+/// Builds the node tree from the events the same way browsers do. Closes the elements
+/// which closing tags are omitted in the code.
+struct TreeBuilder<'a> {
+    settings: &'a LoadSettings,
+
+    /// Elements that were opened but are not closed yet. The first node is always the root.
+    /// It is followed by the context element when parsing a fragment.
+    open: Vec<Node>,
+
+    /// Index of the first element in `open` that can be closed. Elements below it are never
+    /// closed nor searched by the closing tags.
+    base: usize,
+
+    /// Problems in the code the builder has recovered from, including the ones found by
+    /// the tokenizer.
+    diagnostics: Vec<Error>,
+
+    /// How many diagnostics were checked for the problem parsing fails on.
+    checked: usize,
+
+    /// The first problem parsing fails on. Nothing is parsed after it.
+    error: Option<Error>,
+
+    /// Position of the token being processed. Elements which closing tags are missing are
+    /// reported at this position.
+    position: Position,
+
+    /// Code that gives no node and waits for the next node or closing tag to be kept before
+    /// it. Only used in lossless mode.
+    dropped: String,
+}
+
 impl IntoIterator for Children {
 
     type Item = NodeAccess;
@@ -207,33 +603,6 @@ impl DerefMut for Children {
 
 impl Children {
 
-    fn iter_to_owned<T: IntoIterator<Item = Node>>(iter: T, capacity: usize) -> Children {
-        let mut arr = Vec::with_capacity(capacity);
-        for child in iter {
-            arr.push(NodeAccess::new_owned(child));
-        }
-
-        Children(arr)
-    }
-
-    fn iter_to_shared<T: IntoIterator<Item = Node>>(iter: T, capacity: usize) -> Children {
-        let mut arr = Vec::with_capacity(capacity);
-        for child in iter {
-            arr.push(NodeAccess::new_shared(child));
-        }
-
-        Children(arr)
-    }
-
-     fn iter_to<T: IntoIterator<Item = Node>>(children_type: &ChildrenType, iter: T, capacity: usize)
-            -> Children {
-        use ChildrenType::*;
-        match children_type {
-            Owned       => Children::iter_to_owned(iter, capacity),
-            Sharable => Children::iter_to_shared(iter, capacity),
-        }
-    }
-
     /// Get sharable children by cloning data. All children and their children will get
     /// sharable.
     pub fn to_all_sharable(&self) -> Self {
@@ -296,6 +665,34 @@ impl PartialEq for NodeAccess {
     }
 }
 
+impl PartialEq for Node {
+
+    fn eq(&self, other: &Node) -> bool {
+        self.start == other.start
+            && self.text == other.text
+            && self.end == other.end
+            && self.children == other.children
+            && self.markup == other.markup
+    }
+}
+
+impl PartialEq for OpeningTag {
+
+    fn eq(&self, other: &OpeningTag) -> bool {
+        self.empty == other.empty
+            && self.name == other.name
+            && self.attrs == other.attrs
+            && self.namespace == other.namespace
+    }
+}
+
+impl PartialEq for Attribute {
+
+    fn eq(&self, other: &Attribute) -> bool {
+        self.name == other.name && self.value == other.value && self.namespace == other.namespace
+    }
+}
+
 impl Deref for NodeAccess {
 
     type Target = Node;
@@ -320,6 +717,14 @@ impl NodeAccess {
         NodeAccess::Sharable(arc)
     }
 
+    fn new(node: Node, children_type: &ChildrenType) -> NodeAccess {
+        use ChildrenType::*;
+        match children_type {
+            Owned       => NodeAccess::new_owned(node),
+            Sharable    => NodeAccess::new_shared(node),
+        }
+    }
+
     /// Try to access node mutably. If this node is owned then this is possible. For sharable nodes
     /// they can be accessed mutable only if they still were not shared.
     pub fn try_mut(&mut self) -> Option<&mut Node> {
@@ -388,367 +793,128 @@ impl Node {
         Default::default()
     }
 
+    /// Create text node. Text should not be escaped, it gets escaped when converting the node
+    /// to HTML.
+    pub fn new_text(text: &str) -> Self {
+        Node {
+            text: Some(String::from(text)),
+            ..Default::default()
+        }
+    }
+
     /// Load node tree from HTML string.
     ///
     /// The root node has no start, end or text elements. It does have only children in it.
     /// When passing empty code, None will be returned.
-    /// If there is an error parsing the HTML, then this function will fail and return the error
-    /// type that occurred.
+    /// If there is an error parsing the HTML in strict mode, then this function will fail and
+    /// return the error type that occurred.
     pub fn from_html(html: &str, settings: &LoadSettings) -> Result<Option<Node>, Error> {
-        let events = Self::collect_events(html);
-        let children = {
-            let mut nodes = LinkedList::new();
-            let mut iter = events.iter();
-            loop {
-                let node = Self::next_node(&mut iter, settings);
-                if node.is_none() {
-                    break;
-                }
-                nodes.push_back(node.unwrap());
-            }
+        Self::from_html_with_diagnostics(html, settings).map(|(root, _)| root)
+    }
 
-            let len = nodes.len();
-            Children::iter_to(&settings.children_type, nodes.into_iter(), len)
-        };
+    /// Load node tree from HTML string like [`from_html`] does. The problems that were found
+    /// in the code while parsing in recovering mode are returned along with the tree in the
+    /// order they appear in the code.
+    pub fn from_html_with_diagnostics(html: &str, settings: &LoadSettings)
+        -> Result<(Option<Node>, Vec<Error>), Error>
+    {
+        let mut events = Events::new(html, settings);
+        let mut builder = TreeBuilder::new(settings);
+        builder.process_all(&mut events, false);
 
-        if children.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(Node {
-                children,
-                start: None,
-                end: None,
-                text: None,
-            }))
-        }
+        let (root, diagnostics) = builder.finish()?;
+        Ok((root.into_tree(), diagnostics))
     }
 
-    fn collect_events(html: &str) -> LinkedList<Event> {
-        use Event::*;
-
-        let mut reader = Reader::from_str(html);
-        let mut buf = Vec::new();
-        let mut list = LinkedList::new();
-        reader.check_end_names(false);
+    /// Load node tree from the reader of HTML code in UTF-8. The code is parsed while it is
+    /// being read so it never gets loaded in memory as a whole.
+    ///
+    /// Works like [`from_html`] otherwise. Failure to read the code is reported as an error
+    /// of kind `ErrorKind::Other`.
+    pub fn from_reader<R: BufRead>(reader: R, settings: &LoadSettings)
+        -> Result<Option<Node>, Error>
+    {
+        Self::from_reader_with_diagnostics(reader, settings).map(|(root, _)| root)
+    }
+
+    /// Load node tree from the reader of HTML code in UTF-8 along with the problems found in
+    /// the code, like [`from_html_with_diagnostics`] does. See [`from_reader`].
+    pub fn from_reader_with_diagnostics<R: BufRead>(mut reader: R, settings: &LoadSettings)
+        -> Result<(Option<Node>, Vec<Error>), Error>
+    {
+        let mut parser = Parser::new(settings);
         loop {
-            let event
-                = Self::process_next_event(reader.read_event(&mut buf));
-            if event.is_err() {
-                break;
-            }
-
-            let event = event.unwrap();
-            if event.is_some() {
-                list.push_back(event.unwrap());
-            }
-        }
-
-        // Remove trailing empty text on newlines.
-        let fixed_list = {
-            let trim_start = |s: String| {
-                if s.is_empty() {
-                    return s;
-                }
-
-                let mut iter = s.chars();
-                let first = iter.next().unwrap();
-                if first == '\n' {
-                    String::from(s.trim_start())
-                } else if first == '\t' || first == ' ' {
-                    while let Some(ch) = iter.next() {
-                        if ch != '\t' && ch != ' ' && ch != '\n' {
-                            return s;
-                        }
-                    }
-                    String::from(s.trim_start())
-                } else {
-                    s
-                }
-            };
-            let trim_end = |s: String| {
-                let bytes = s.as_bytes();
-                let mut memchr = memchr_iter('\n' as _, bytes);
-                if let Some(_) = memchr.next() {
-                    String::from(s.trim_end())
-                } else {
-                    s
-                }
+            let len = {
+                let bytes = match reader.fill_buf() {
+                    Ok(bytes) => bytes,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        let kind = ErrorKind::Other(e.to_string());
+                        return Err(Error::new(kind, parser.position()));
+                    },
+                };
+                parser.feed(bytes)?;
+                bytes.len()
             };
-
-            let mut fixed_list = LinkedList::new();
-            for i in list {
-                if let Text(e) = i {
-                    let text = std::str::from_utf8(e.escaped()).unwrap();
-                    let text = String::from(text);
-                    let s = trim_start(text);
-                    let s = trim_end(s);
-                    if !s.is_empty() {
-                        let content = Vec::from(s.as_bytes());
-                        let new = Text(BytesText::from_plain(&content)).into_owned();
-                        fixed_list.push_back(new);
-                    }
-                } else {
-                    fixed_list.push_back(i);
-                }
+            if len == 0 {
+                break;
             }
-            fixed_list
-        };
-
-        fixed_list
-    }
-
-    fn process_next_event(event: quick_xml::Result<Event>) -> Result<Option<Event<'static>>, ()> {
-        use Event::*;
-
-        if event.is_err() {
-            return Err(());
-        }
-        let event: Event = event.unwrap();
-
-        match event {
-            Start(e) => {
-                let vec = e.to_vec();
-                let e = BytesStart::borrowed(
-                    &vec, e.name().len()
-                ).into_owned();
-                Ok(Some(Start(e)))
-            },
-            End(e) => {
-                let vec = e.to_vec();
-                let e = BytesEnd::borrowed(&vec).into_owned();
-                Ok(Some(End(e)))
-            },
-            Empty(e) => {
-                let vec = e.to_vec();
-                let e = BytesStart::borrowed(
-                    &vec, e.name().len()
-                ).into_owned();
-                Ok(Some(Empty(e)))
-            },
-            Text(e) => {
-                let vec = e.to_vec();
-                let e = BytesText::from_plain(&vec).into_owned();
-                Ok(Some(Text(e)))
-            },
-            DocType(_) => Ok(None),
-            Eof => Err(()),
-            _ => Err(()),
+            reader.consume(len);
         }
+        parser.finish_with_diagnostics()
     }
 
-    /// Function to read next node and it's children from event iterator.
-    #[allow(unused_assignments)]
-    fn next_node(
-            iter: &mut std::collections::linked_list::Iter<Event>,
-            settings: &LoadSettings) -> Option<Node> {
-        use Event::*;
-
-        let mut biter = iter.clone();
-        let peek = biter.next();
-        if peek.is_none() {
-            return None;
-        }
-        let peek = peek.unwrap();
-        match peek {
-            Start(e) => {
-                iter.next(); // Confirm reading this event.
-
-                let start = Some({
-                    let name = String::from(unsafe {
-                        std::str::from_utf8_unchecked(
-                            &*e.name()).split_whitespace().next().unwrap()
-                    });
-
-                    let mut attrs = LinkedList::new();
-                    for attr in e.attributes() {
-                        if let Err(_) = attr {
-                            continue;
-                        }
-                        let attr = attr.unwrap();
-
-                        let name = String::from(unsafe {
-                            std::str::from_utf8_unchecked(attr.key)
-                        });
-                        let attr = Attribute::from_name_and_str_values(
-                            name,
-                            unsafe { std::str::from_utf8_unchecked(&*attr.value) }
-                        );
-                        attrs.push_back(attr);
-                    }
-                    let mut attrsvec = Vec::with_capacity(attrs.len());
-                    for attr in attrs {
-                        attrsvec.push(attr);
-                    }
-
-                    OpeningTag {
-                        empty: false,
-                        name,
-                        attrs: attrsvec
-                    }
-                });
-                let mut text = {
-                    let peek = biter.next();
-                    if let Some(peek) = peek {
-                        match peek {
-                            Text(e) => {
-                                iter.next(); // Confirm reading event.
-                                let s = unsafe { std::str::from_utf8_unchecked(e) };
-                                Some(String::from(s))
-                            }
-                            _ => {
-                                biter = iter.clone(); // Revert read.
-                                None
-                            }
-                        }
-                    } else {
-                        biter = iter.clone(); // Revert read.
-                        None
-                    }
-                };
-                let children = {
-                    let mut children = LinkedList::new();
-                    loop {
-                        let child = Self::next_node(iter, settings);
-                        if let Some(child) = child {
-                            children.push_back(child);
-                        } else {
-                            break;
-                        }
-                    }
-                    biter = iter.clone(); // Apply changes of iter.
-
-                    // Check whether to store text in separate node or in the same node.
-                    // Text cannot be mixed with children as this will loose information about
-                    // order of occurrences of children tags and the text values. So
-                    // in this case all texts are saved as nodes on their own in children array.
-                    // We only need to check already read text field as if it is read then it
-                    // precedes any children nodes. All other texts are already on their own
-                    // children nodes because of recursive call of this function.
-                    if text.is_some() {
-                        if !children.is_empty() || settings.all_text_separately {
-                            // Store as separate node as first child as it actually is the first
-                            // thing that was read.
-                            children.push_front(Node {
-                                start: None,
-                                end: None,
-                                text,
-                                children: Default::default(),
-                            });
-                            text = None;
-                        }
-                    }
-
-                    let len = children.len();
-                    Children::iter_to(
-                        &settings.children_type,
-                        children,
-                        len
-                    )
-                };
-                let end = {
-                    if start.is_some() { // Only opening tag can have a closing tag.
-                        let peek = biter.next();
-                        if peek.is_none() {
-                            None
-                        } else {
-                            match peek.unwrap() {
-                                End(e) => {
-                                    // Check if names are same. If not - discard and return None.
-                                    if e.name() == start.as_ref().unwrap().name().as_bytes() {
-                                        iter.next(); // Confirm reading end tag.
-                                        let s = unsafe {
-                                            std::str::from_utf8_unchecked(e.name())
-                                        };
-                                        Some(String::from(s))
-                                    } else {
-                                        biter = iter.clone();
-                                        None
-                                    }
-                                },
-                                _ => {
-                                    biter = iter.clone();
-                                    None
-                                }
-                            }
-                        }
-                    } else {
-                        None
-                    }
-                };
-
-                let e = Some(Node {
-                    start,
-                    end,
-                    text,
-                    children,
-                });
-                e
-            },
-            Text(e) => {
-                iter.next();
-
-                Some(Node {
-                    start: None,
-                    end: None,
-                    children: Default::default(),
-
-                    text: Some(
-                        String::from(unsafe { std::str::from_utf8_unchecked(&*e) })
-                    ),
-                })
-            },
-            Empty(e) => {
-                iter.next();
-
-                let start = Some({
-                    let name = e.name();
-                    let name = String::from(unsafe {
-                        std::str::from_utf8_unchecked(&*name)
-                            .split_whitespace().next().unwrap()
-                    });
-
-                    OpeningTag {
-                        empty: true,
-                        name,
-                        attrs: Default::default(),
-                    }
-                });
+    /// Load node tree from HTML code in unknown encoding. The encoding is detected from the byte
+    /// order mark, then from `<meta charset>` or `<meta http-equiv="Content-Type">` tags in
+    /// the first 1024 bytes. If nothing is found, the default encoding from settings is used.
+    /// The tree is returned along with the encoding the code was decoded with.
+    ///
+    /// Invalid byte sequences are replaced with U+FFFD replacement character.
+    pub fn from_html_bytes(bytes: &[u8], settings: &LoadSettings)
+        -> Result<(Option<Node>, &'static Encoding), Error>
+    {
+        let (encoding, bom_len) = charset::detect(bytes, settings.default_encoding);
+        let (html, _) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+        Self::from_html(&html, settings).map(|root| (root, encoding))
+    }
+
+    /// Load nodes from HTML fragment that is a content of the element with given name, the way
+    /// `innerHTML` of such element is parsed. E.g. table cells are read as cells of the row
+    /// for `tr` context and the content of `textarea` context is read as a text.
+    ///
+    /// The root node contains the nodes of the fragment. The context element itself is not
+    /// included. Works like [`from_html`] otherwise.
+    pub fn from_html_fragment(html: &str, context: &str, settings: &LoadSettings)
+        -> Result<Option<Node>, Error>
+    {
+        let mut tokenizer = Tokenizer::new(html);
+        tokenizer.enter_element(context);
+        let mut events = Events::with_tokenizer(tokenizer, settings);
+        let mut builder = TreeBuilder::for_fragment(settings, context);
+        builder.process_all(&mut events, false);
 
-                Some(Node {
-                    start,
-                    end: None,
-                    text: None,
-                    children: Default::default(),
-                })
-            },
-            _ => None
-        }
+        let (root, _) = builder.finish()?;
+        Ok(root.into_tree())
     }
 
     /// Load the first node from HTML string without wrapping node to the tree with root (empty
     /// first node). Just return the exact single node.
     ///
     /// # Failures
-    /// None is returned if string does not contain any node (is empty).
-    pub fn from_html_first(html: &str, settings: &LoadSettings) -> Option<Self> {
-        let events = Self::collect_events(html);
-        let mut iter = events.iter();
-        let node = {
-            let mut result;
-            loop {
-                let node = Self::next_node(&mut iter, settings);
-                if node.is_none() {
-                    result = None;
-                    break;
-                } else {
-                    result = node;
-                    break;
-                }
-            }
-            result
-        };
-
-        node
+    /// None is returned if string does not contain any node (is empty). If there is an error
+    /// parsing the HTML, then the error is returned.
+    pub fn from_html_first(html: &str, settings: &LoadSettings) -> Result<Option<Self>, Error> {
+        let mut events = Events::new(html, settings);
+        let mut builder = TreeBuilder::new(settings);
+        builder.process_all(&mut events, true);
+        builder.keep_first_node();
+
+        let (root, _) = builder.finish()?;
+        let first = root.children.0.into_iter().next().map(|first| match first {
+            NodeAccess::Owned(n) => n,
+            NodeAccess::Sharable(n) => Arc::try_unwrap(n).unwrap_or_else(|n| (*n).clone()),
+        });
+        Ok(first)
     }
 
     /// Start tag information.
@@ -774,44 +940,122 @@ impl Node {
         }
     }
 
-    /// Children tags of this node.
-    pub fn children(&self) -> &Children {
-        &self.children
+    /// Text as it is written in the HTML code, without decoding character references like
+    /// `&amp;`. For nodes that were not loaded from the code this is the same as `text`.
+    pub fn raw_text(&self) -> Option<&str> {
+        self.raw_text.as_deref().or(self.text())
     }
 
-    /// The name of the tag that is represented by the node.
-    pub fn tag_name(&self) -> Option<&str> {
-        if let Some(ref start) = self.start {
-            Some(&start.name)
+    /// Text with all character references decoded, e.g. `Tom &amp; Jerry` becomes
+    /// `Tom & Jerry`.
+    pub fn decoded_text(&self) -> Option<Cow<'_, str>> {
+        if self.undecoded {
+            self.text().map(|s| entities::decode(s, false))
+        } else {
+            // Text that was not loaded from the code never contains references.
+            self.text().map(Cow::Borrowed)
+        }
+    }
+
+    /// Part of the code with the whole node. For element it starts with the opening tag and
+    /// ends with the closing tag or with the last child if the closing tag was omitted.
+    /// None if the node was not loaded from the code.
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Part of the code with the closing tag if there is one.
+    pub fn end_span(&self) -> Option<Span> {
+        self.end_span
+    }
+
+    /// Content of the node if it is neither a tag nor a text.
+    pub fn markup(&self) -> Option<&Markup> {
+        self.markup.as_ref()
+    }
+
+    /// Text of the comment if this node is a comment.
+    pub fn comment(&self) -> Option<&str> {
+        if let Some(Markup::Comment(ref s)) = self.markup {
+            Some(s)
         } else {
             None
         }
     }
 
-    /// Start tag attributes.
-    pub fn attributes(&self) -> Option<&Vec<Attribute>> {
-        if let Some(ref start) = self.start {
-            Some(&start.attrs)
+    /// Document type, e.g. `html` for `<!DOCTYPE html>`. If this is the root node, then
+    /// the document type is searched among its children.
+    pub fn doctype(&self) -> Option<&str> {
+        if let Some(Markup::Doctype(ref s)) = self.markup {
+            Some(s.trim())
+        } else if self.is_root() {
+            self.children.iter().filter_map(|child| child.doctype()).next()
         } else {
             None
         }
     }
 
-    /// Find attribute by it's name.
-    pub fn attribute_by_name(&self, key: &str) -> Option<&Attribute> {
-        if let Some(ref start) = self.start {
-            for attr in start.attributes() {
-                if attr.name() == key {
-                    return Some(attr);
-                }
-            }
+    /// Content of the XML declaration, e.g. `xml version="1.0"`. If this is the root node,
+    /// then the declaration is searched among its children.
+    pub fn declaration(&self) -> Option<&str> {
+        if let Some(Markup::Declaration(ref s)) = self.markup {
+            Some(s)
+        } else if self.is_root() {
+            self.children.iter().filter_map(|child| child.declaration()).next()
+        } else {
+            None
         }
-        None
     }
 
-    /// Try saving given attribute in this node.
-    ///
-    /// # Failure
+    /// Content of the processing instruction if this node is one.
+    pub fn processing_instruction(&self) -> Option<&str> {
+        if let Some(Markup::Instruction(ref s)) = self.markup {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    /// Raw content of the CDATA section if this node is one.
+    pub fn cdata(&self) -> Option<&str> {
+        if let Some(Markup::CData(ref s)) = self.markup {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    /// Children tags of this node.
+    pub fn children(&self) -> &Children {
+        &self.children
+    }
+
+    /// The name of the tag that is represented by the node.
+    pub fn tag_name(&self) -> Option<&str> {
+        if let Some(ref start) = self.start {
+            Some(&start.name)
+        } else {
+            None
+        }
+    }
+
+    /// Start tag attributes.
+    pub fn attributes(&self) -> Option<&Vec<Attribute>> {
+        if let Some(ref start) = self.start {
+            Some(&start.attrs)
+        } else {
+            None
+        }
+    }
+
+    /// Find attribute by it's name.
+    pub fn attribute_by_name(&self, key: &str) -> Option<&Attribute> {
+        self.start.as_ref().and_then(|start| start.attribute_by_name(key))
+    }
+
+    /// Try saving given attribute in this node.
+    ///
+    /// # Failure
     /// If this attribute is already present then this function will not change it.
     /// If you need to overwrite the attribute anyway use [`overwrite_attribute`].
     pub fn put_attribute(&mut self, attr: Attribute) -> Result<(), Attribute> {
@@ -834,9 +1078,9 @@ impl Node {
         let attrs = &mut self.start.as_mut().unwrap().attrs;
         while i < attrs.len() {
             let this = attrs.get_mut(i).unwrap();
-            if attr.name == this.name {
+            if attr.name.eq_ignore_ascii_case(&this.name) {
                 // Found. Overwrite.
-                this.values = attr.values;
+                this.store(attr.value);
                 return;
             }
             i += 1;
@@ -856,43 +1100,130 @@ impl Node {
     }
 
     /// Convert this node and all it's children into HTML string.
+    ///
+    /// Text and attribute values are escaped so that the result is read back as the same
+    /// values. Texts that were loaded from HTML code are written as they were in the code.
+    /// Content that would end a script, comment or CDATA section early is broken up, e.g.
+    /// `</script>` in a script becomes `<\/script>`, and attributes with names that cannot
+    /// be written are skipped.
     pub fn to_string(&self) -> String {
         let mut s = String::new();
+        self.write_html(&mut s, None);
+        s.shrink_to_fit();
+        s
+    }
+
+    /// Write HTML code of this node to the string. `raw_text` is the name of the raw text
+    /// element like `<script>` which content must not be escaped, if this node is inside it.
+    fn write_html(&self, s: &mut String, raw_text: Option<&str>) {
+        if let Some(ref raw) = self.raw_before {
+            *s += raw;
+        }
         if let Some(name) = self.tag_name() {
-            s += "<";
-            s += &name;
-
-            let attrs = &self.start.as_ref().unwrap().attrs;
-            for attr in attrs {
-                s += " ";
-                s += &attr.name;
-                s += "=\"";
-                s += &attr.values_to_string();
-                s += "\"";
+            let start = self.start.as_ref().unwrap();
+            match start.raw_start {
+                Some(ref raw) => *s += raw,
+                None => {
+                    *s += "<";
+                    *s += name;
+                },
             }
 
-            if self.start.as_ref().unwrap().is_self_closing() {
-                s += "/";
+            for (i, attr) in start.attrs.iter().enumerate() {
+                start.write_dropped(s, i);
+                if let Some(ref raw) = attr.raw {
+                    *s += raw;
+                    continue;
+                } else if !is_valid_attribute_name(&attr.name) {
+                    continue;
+                }
+                *s += " ";
+                *s += &attr.name;
+                if let Some(ref value) = attr.value {
+                    *s += "=\"";
+                    *s += &entities::escape(value, true);
+                    *s += "\"";
+                }
+            }
+            start.write_dropped(s, start.attrs.len());
+
+            if let Some(ref raw) = start.raw_end {
+                *s += raw;
+            } else {
+                if start.is_self_closing() {
+                    *s += "/";
+                }
+                *s += ">";
             }
 
-            s += ">";
+            if start.raw_end.is_none() && is_one_of(name, PREFORMATTED_ELEMENTS) {
+                // Line break right after the opening tag is ignored when the code is parsed so
+                // the line break of the content needs another one before it.
+                let first = self.children.first().map(|child| &**child).unwrap_or(self);
+                let text = first.raw_text.as_ref().or(first.text.as_ref());
+                if text.map_or(false, |text| text.starts_with('\n')) {
+                    *s += "\n";
+                }
+            }
         }
-        if let Some(ref text) = self.text {
-            s += text;
+
+        let raw_text = match self.start {
+            // Elements like `<style>` in SVG have usual content.
+            Some(ref start) if start.namespace == Namespace::Html
+                && is_raw_text_element(&start.name) => Some(start.name.as_str()),
+            _ => raw_text,
+        };
+        if let Some(ref raw) = self.raw_text {
+            // Text from the code is already escaped.
+            *s += raw;
+        } else if let Some(ref text) = self.text {
+            match raw_text {
+                Some(element) => *s += &escape_raw_text(text, element),
+                None => *s += &entities::escape(text, false),
+            }
+        } else if let Some(ref raw) = self.raw_markup {
+            *s += raw;
+        } else if let Some(ref markup) = self.markup {
+            match markup {
+                Markup::Comment(comment) => {
+                    // Comment cannot start with `>` or `->` and cannot contain its end.
+                    *s += "<!--";
+                    if comment.starts_with('>') || comment.starts_with("->") {
+                        *s += " ";
+                    }
+                    *s += &comment.replace("--!>", "--! >").replace("-->", "-- >");
+                    *s += "-->";
+                },
+                Markup::Doctype(doctype) => {
+                    *s += "<!DOCTYPE";
+                    *s += &doctype.replace('>', "");
+                    *s += ">";
+                },
+                Markup::Declaration(text) | Markup::Instruction(text) => {
+                    *s += "<?";
+                    *s += &text.replace("?>", "? >");
+                    *s += "?>";
+                },
+                Markup::CData(text) => {
+                    // The end of the section is split between two sections.
+                    *s += "<![CDATA[";
+                    *s += &text.replace("]]>", "]]]]><![CDATA[>");
+                    *s += "]]>";
+                },
+            }
         }
 
         for child in self.children.iter() {
-            s += &child.to_string();
+            child.write_html(s, raw_text);
         }
 
-        if let Some(ref end) = self.end {
-            s += "</";
-            s += end;
-            s += ">";
+        if let Some(ref raw) = self.raw_end {
+            *s += raw;
+        } else if let Some(ref end) = self.end {
+            *s += "</";
+            *s += end;
+            *s += ">";
         }
-
-        s.shrink_to_fit();
-        s
     }
 
     /// Change name of opening and closing tags (if any).
@@ -905,6 +1236,7 @@ impl Node {
     pub fn change_opening_name(&mut self, name: &str) {
         if let Some(ref mut start) = self.start {
             start.name = String::from(name);
+            start.raw_start = None;
         }
     }
 
@@ -912,6 +1244,7 @@ impl Node {
     pub fn change_closing_name(&mut self, name: &str) {
         if let Some(ref mut end) = self.end {
             *end = String::from(name);
+            self.raw_end = None;
         }
     }
 
@@ -926,7 +1259,15 @@ impl Node {
             start: self.start.clone(),
             end: self.end.clone(),
             text: self.text.clone(),
+            raw_text: self.raw_text.clone(),
+            undecoded: self.undecoded,
             children: Default::default(),
+            markup: self.markup.clone(),
+            span: self.span,
+            end_span: self.end_span,
+            raw_end: self.raw_end.clone(),
+            raw_markup: self.raw_markup.clone(),
+            raw_before: self.raw_before.clone(),
         }
     }
 
@@ -940,7 +1281,7 @@ impl Node {
     /// # Failures
     /// If this node already is root it is returned back in Err.
     pub fn wrap_to_root(self) -> Result<Self, Self> {
-        if self.start.is_none() && self.text.is_none() {
+        if self.is_root() {
             return Err(self);
         }
 
@@ -951,319 +1292,1552 @@ impl Node {
 
     /// Check whether this node is the root of the tree.
     pub fn is_root(&self) -> bool {
-        self.text.is_none() && self.start.is_none() && self.text.is_none()
+        self.text.is_none() && self.start.is_none() && self.markup.is_none()
     }
-}
-
-impl<'a> ChildrenFetch<'a> {
 
-    /// Get children fetcher for given node to find children that apply to some criteria.
-    pub fn for_node(node: &'a Node) -> Self {
-        ChildrenFetch {
-            node,
-            tag:        None,
-            key:        None,
-            value:      None,
-            value_part: None,
+    /// Tree with this root node. None if the code gave no nodes, unless the code is kept
+    /// in lossless mode anyway.
+    fn into_tree(self) -> Option<Node> {
+        if self.children.is_empty() && self.raw_end.is_none() {
+            None
+        } else {
+            Some(self)
         }
     }
+}
 
-    /// Clone the fetcher with already set criteria but for given different node.
-    pub fn same_for_node(&self, node: &'a Node) -> Self {
-        let mut new = self.clone();
-        new.node = node;
-        new
-    }
-
-    /// Tag to search for.
-    pub fn tag(mut self, tag: &'a str) -> Self {
-        self.tag = Some(tag);
-        self
-    }
-
-    pub fn set_tag(&mut self, tag: &'a str) {
-        self.tag = Some(tag);
-    }
-
-    /// Key to search for.
-    pub fn key(mut self, key: &'a str) -> Self {
-        self.key = Some(key);
-        self
-    }
-
-    pub fn set_key(&mut self, key: &'a str) {
-        self.key = Some(key);
-    }
-
-    /// Exact value to search for.
-    pub fn value(mut self, value: &'a str) -> Self {
-        self.value = Some(value);
-        self
-    }
+impl<'a> Events<'a> {
 
-    pub fn set_value(&mut self, value: &'a str) {
-        self.value = Some(value);
+    /// Iterate over the events of given code that is read with given settings.
+    pub fn new(html: &'a str, settings: &'a LoadSettings) -> Self {
+        Self::with_tokenizer(Tokenizer::new(html), settings)
     }
 
-    /// If exact value is not set then this defines a part of the value separated with whitespaces
-    /// to be found. If `value` is, however, set then this field is ignored entirely.
-    pub fn value_part(mut self, part: &'a str) -> Self {
-        self.value_part = Some(part);
-        self
+    /// Take the problems in the code that were recovered from in the events read so far.
+    /// Problems are kept until they are taken, so take them now and then when reading long
+    /// code with many problems.
+    pub fn take_diagnostics(&mut self) -> Vec<Error> {
+        std::mem::take(&mut self.diagnostics)
     }
 
-    pub fn set_value_part(&mut self, part: &'a str) {
-        self.value_part = Some(part);
+    fn with_tokenizer(tokenizer: Tokenizer<'a>, settings: &'a LoadSettings) -> Self {
+        Events {
+            tokenizer,
+            settings,
+            preformatted: 0,
+            skip_newline: false,
+            foreign: 0,
+            failed: false,
+            diagnostics: Vec::new(),
+            source: String::new(),
+            dropped: String::new(),
+        }
     }
 
-    /// Get all children and their children that apply to the criteria.
-    /// This function does not check the parent node!
-    pub fn fetch(self) -> LinkedList<&'a NodeAccess> {
-        fn sub(criteria: ChildrenFetch) -> LinkedList<&NodeAccess> {
-            let mut list = LinkedList::new();
+    /// Read next event that is available now. Problems in the code are left in the tokenizer.
+    fn next_event(&mut self) -> Option<(Event, Span)> {
+        use Token::*;
 
-            for child in criteria.node.children.iter() {
-                // Filter on tag if present.
-                if let Some(tag) = criteria.tag {
-                    if child.tag_name().unwrap_or("") != tag {
+        loop {
+            let skip_newline = std::mem::replace(&mut self.skip_newline, false);
+            self.tokenizer.set_foreign(self.foreign > 0);
+            let (mut token, mut span) = match self.tokenizer.next_token() {
+                Some(next) => next,
+                None => {
+                    // The newline may come with the rest of the code.
+                    self.skip_newline = skip_newline;
+                    self.drop_token();
+                    return None;
+                },
+            };
+            if let Text(ref mut text) | RawText { ref mut text, .. } = token {
+                // Line break right after the opening tag of preformatted element is ignored.
+                let newline = if text.starts_with("\r\n") { 2 } else { 1 };
+                if skip_newline && (text.starts_with('\n') || text.starts_with("\r\n")) {
+                    if self.settings.lossless {
+                        self.dropped += &text[..newline];
+                    }
+                    span.start = span.start.advanced(&text[..newline]);
+                    text.drain(..newline);
+                    if text.is_empty() {
                         continue;
                     }
                 }
-                // Filter value and value_part by criteria. Append filtered values to list.
-                let mut check_value_criteria = |attr: &Attribute| {
-                    if let Some(value) = criteria.value {
-                        if attr.values_to_string() == value {
-                            list.push_back(child);
-                        }
-                    } else if let Some(part) = criteria.value_part {
-                        let iter = attr.values().iter();
-                        for i in iter {
-                            if i == part {
-                                list.push_back(child);
-                                break;
-                            }
-                        }
-                    } else {
-                        // No value expected and finding of a key is enough.
-                        list.push_back(child);
+            }
+
+            let event = match token {
+                StartTag { mut name, mut attrs, self_closing } => {
+                    let is_foreign = is_one_of(&name, FOREIGN_ELEMENTS);
+                    let keep_case = self.settings.keep_foreign_case
+                        && (self.foreign > 0 || is_foreign);
+                    if !keep_case || is_foreign {
+                        name.make_ascii_lowercase();
+                    }
+                    if !keep_case {
+                        attrs.iter_mut().for_each(|attr| attr.name.make_ascii_lowercase());
                     }
-                };
 
-                if let Some(key) = criteria.key {
-                    if let Some(attr) = child.attribute_by_name(key) {
-                        check_value_criteria(attr)
+                    if !self_closing && is_one_of(&name, PREFORMATTED_ELEMENTS) {
+                        self.preformatted += 1;
+                        self.skip_newline = true;
                     }
-                } else {
-                    if let Some(attrs) = child.attributes() {
-                        for attr in attrs {
-                            check_value_criteria(attr)
-                        }
+                    if !self_closing && is_foreign {
+                        self.foreign += 1;
+                    }
+                    let mut tag = OpeningTag::from_token(&name, &attrs, self_closing, span);
+                    if self.settings.lossless {
+                        tag.keep_source(self.tokenizer.source(), &attrs);
+                    }
+                    let policy = self.settings.duplicate_attributes;
+                    for i in tag.resolve_duplicates(policy) {
+                        // Repeated attribute is reported where its name starts.
+                        let code = &self.tokenizer.source()[attrs[i].source.clone()];
+                        let rest = code.trim_start_matches(|c: char| {
+                            c.is_ascii_whitespace() || c == '/'
+                        });
+                        let code = &self.tokenizer.source()[..attrs[i].source.end - rest.len()];
+                        let position = span.start.advanced(code);
+                        let kind = ErrorKind::DuplicateAttribute(attrs[i].name.clone(), policy);
+                        self.tokenizer.add_diagnostic(Error::new(kind, position));
+                    }
+                    Event::StartTag(tag)
+                },
+                EndTag(mut name) => {
+                    let is_foreign = is_one_of(&name, FOREIGN_ELEMENTS);
+                    if !self.settings.keep_foreign_case || self.foreign == 0 || is_foreign {
+                        name.make_ascii_lowercase();
                     }
-                }
 
-                let new_fetch = criteria.same_for_node(&child);
-                let mut nodes = sub(new_fetch);
-                list.append(&mut nodes);
+                    if self.preformatted > 0 && is_one_of(&name, PREFORMATTED_ELEMENTS) {
+                        self.preformatted -= 1;
+                    }
+                    if self.foreign > 0 && is_foreign {
+                        self.foreign -= 1;
+                    }
+                    Event::EndTag(name)
+                },
+                Text(text) if self.preformatted > 0 => self.text_event(text, true),
+                Text(text) => match self.settings.whitespace.apply(text, span) {
+                    Some((text, _)) if self.settings.lossless => {
+                        // Text is written as it was even if whitespace was changed.
+                        let mut event = self.text_event(text, true);
+                        if let Event::Text { ref mut raw_text, .. } = event {
+                            raw_text.clear();
+                            *raw_text += self.tokenizer.source();
+                        }
+                        event
+                    },
+                    Some((text, trimmed)) => {
+                        span = trimmed;
+                        self.text_event(text, true)
+                    },
+                    None => {
+                        self.drop_token();
+                        continue;
+                    },
+                },
+                RawText { text, escapable } => self.text_event(text, escapable),
+                Comment(_) if self.settings.skip_comments => {
+                    self.drop_token();
+                    continue;
+                },
+                Comment(text) => Event::Markup(Markup::Comment(text)),
+                Doctype(text) => Event::Markup(Markup::Doctype(text)),
+                Declaration(text) => Event::Markup(Markup::Declaration(text)),
+                Instruction(text) => Event::Markup(Markup::Instruction(text)),
+                CData(text) => Event::Markup(Markup::CData(text)),
+            };
+            if self.settings.lossless {
+                self.source.clear();
+                self.source += self.tokenizer.source();
             }
+            return Some((event, span));
+        }
+    }
 
-            list
+    /// Keep the code of the token that gives no event in lossless mode.
+    fn drop_token(&mut self) {
+        if self.settings.lossless {
+            self.dropped += self.tokenizer.source();
         }
+    }
 
-        sub(self)
+    /// Text event for the text from the code. Character references are decoded only if
+    /// they are allowed in the text.
+    fn text_event(&self, raw_text: String, escapable: bool) -> Event {
+        let text = if escapable && self.settings.decode_entities {
+            entities::decode(&raw_text, false).into_owned()
+        } else {
+            raw_text.clone()
+        };
+        Event::Text { text, raw_text }
     }
 }
 
-impl<'a> ChildrenFetchMut<'a> {
+impl<'a> Iterator for Events<'a> {
 
-    /// Get children fetcher for given node to find children that apply to some criteria.
-    pub fn for_node(node: &'a Node) -> Self {
-        let inner = ChildrenFetch {
-            node,
-            tag:        None,
-            key:        None,
-            value:      None,
-            value_part: None,
-        };
-        ChildrenFetchMut { inner }
-    }
+    type Item = Result<(Event, Span), Error>;
 
-    /// Get all children and their children that apply to the criteria.
-    pub fn fetch_mut(self) -> LinkedList<&'a mut NodeAccess> {
-        let fetch = self.fetch();
-        let mut result = LinkedList::new();
-        for i in fetch {
-            let a = i as *const NodeAccess as *mut NodeAccess;
-            let a = unsafe { &mut *a };
-            result.push_back(a);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
-        result
-    }
 
-    pub fn fetch(self) -> LinkedList<&'a NodeAccess> {
-        self.inner.fetch()
+        let event = self.next_event();
+        for error in self.tokenizer.take_diagnostics() {
+            if self.settings.fails_on(&error) {
+                self.failed = true;
+                return Some(Err(error));
+            }
+            self.diagnostics.push(error);
+        }
+        event.map(Ok)
     }
+}
 
-    /// Clone the fetcher with already set criteria but for given different node.
-    pub fn same_for_node(&self, node: &'a Node) -> Self {
-        ChildrenFetchMut { inner: self.inner.same_for_node(node) }
-    }
+impl<'a> Parser<'a> {
 
-    /// Key to search for.
-    pub fn key(self, key: &'a str) -> Self {
-        let inner = self.inner.key(key);
-        ChildrenFetchMut { inner }
+    /// Create the parser that loads the code with given settings.
+    pub fn new(settings: &'a LoadSettings) -> Self {
+        Parser {
+            events: Events::with_tokenizer(Tokenizer::incremental(), settings),
+            builder: TreeBuilder::new(settings),
+        }
     }
 
-    /// Exact value to search for.
-    pub fn value(self, value: &'a str) -> Self {
-        let inner = self.inner.value(value);
-        ChildrenFetchMut { inner }
+    /// Parse next part of the code in UTF-8. Tags, character references and characters
+    /// can be split between the parts.
+    ///
+    /// # Failure
+    /// The problem parsing stopped on is returned, so there is no need to feed the rest of
+    /// the code. Parts that are fed after it are ignored and the same error is returned.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if let Some(ref error) = self.builder.error {
+            return Err(error.clone());
+        }
+        self.events.tokenizer.feed(bytes);
+        self.builder.process_all(&mut self.events, false);
+        match self.builder.error {
+            Some(ref error) => Err(error.clone()),
+            None => Ok(()),
+        }
     }
 
-    /// If exact value is not set then this defines a part of the value separated with whitespaces
-    /// to be found. If `value` is, however, set then this field is ignored entirely.
-    pub fn value_part(self, part: &'a str) -> Self {
-        let inner = self.inner.value_part(part);
-        ChildrenFetchMut { inner }
+    /// Parse the rest of the code and get the node tree like [`Node::from_html`] does.
+    pub fn finish(self) -> Result<Option<Node>, Error> {
+        self.finish_with_diagnostics().map(|(root, _)| root)
     }
-}
-
-impl OpeningTag {
 
-    /// Name of this tag.
-    pub fn name(&self) -> &str {
-        &self.name
-    }
+    /// Parse the rest of the code and get the node tree along with the problems found in
+    /// the code like [`Node::from_html_with_diagnostics`] does.
+    pub fn finish_with_diagnostics(mut self) -> Result<(Option<Node>, Vec<Error>), Error> {
+        self.events.tokenizer.end_input();
+        self.builder.process_all(&mut self.events, false);
 
-    /// Attributes of tag.
-    pub fn attributes(&self) -> &Vec<Attribute> {
-        &self.attrs
+        let (root, diagnostics) = self.builder.finish()?;
+        Ok((root.into_tree(), diagnostics))
     }
 
-    pub fn is_self_closing(&self) -> bool {
-        self.empty
+    /// Position right after the code that was already parsed.
+    fn position(&self) -> Position {
+        self.builder.position
     }
 }
 
-impl Attribute {
-
-    /// Create from a name and values passed as single string that are separated by whitespaces.
-    pub fn from_name_and_str_values(name: String, values: &str) -> Self {
-        let values = {
-            let mut list = LinkedList::new();
-            for val in values.split_whitespace() {
-                list.push_back(String::from(val));
-            }
-
-            let mut vec = Vec::with_capacity(list.len());
-            for val in list {
-                vec.push(val);
-            }
+impl<'a> TreeBuilder<'a> {
+
+    fn new(settings: &'a LoadSettings) -> Self {
+        TreeBuilder {
+            settings,
+            open: vec![Node::new()],
+            base: 1,
+            diagnostics: Vec::new(),
+            checked: 0,
+            error: None,
+            position: Position::code_start(),
+            dropped: String::new(),
+        }
+    }
 
-            vec
+    /// Builder of the fragment that is a content of the element with given name.
+    fn for_fragment(settings: &'a LoadSettings, context: &str) -> Self {
+        let context = Node {
+            start: Some(OpeningTag {
+                empty: false,
+                name: context.to_ascii_lowercase(),
+                attrs: Vec::new(),
+                span: None,
+                namespace: Namespace::Html,
+                raw_start: None,
+                raw_end: None,
+                raw_dropped: Vec::new(),
+            }),
+            ..Default::default()
         };
 
-        Attribute {
-            name,
-            values
+        let mut builder = TreeBuilder::new(settings);
+        let mut context = context;
+        builder.resolve_namespaces(context.start.as_mut().unwrap());
+        builder.open.push(context);
+        builder.base = 2;
+        builder
+    }
+
+    /// Add all tokens that tokenizer can read now to the tree. Stops after the first node is
+    /// complete if `first_only` is set.
+    fn process_all(&mut self, events: &mut Events, first_only: bool) {
+        while self.error.is_none() && !(first_only && self.has_first_node()) {
+            // Elements closed implicitly are only known here, so the open ones decide how the
+            // code is read.
+            events.preformatted = self.open.iter()
+                .filter_map(|node| node.start.as_ref())
+                .filter(|tag| tag.namespace == Namespace::Html)
+                .filter(|tag| is_one_of(tag.name(), PREFORMATTED_ELEMENTS))
+                .count();
+            // Foreign element that is still open is counted once no matter how deep it is.
+            events.foreign = usize::from(self.content_namespace() != Namespace::Html);
+            let next = events.next_event();
+            self.diagnostics.append(&mut events.tokenizer.take_diagnostics());
+            self.keep_dropped(std::mem::take(&mut events.dropped));
+            let more = match next {
+                Some((event, span)) => {
+                    let source = std::mem::take(&mut events.source);
+                    self.process(event, span, source);
+                    true
+                },
+                None => false,
+            };
+            self.check_diagnostics();
+            if !more {
+                break;
+            }
         }
     }
 
-    /// Create from a name and values passed as array of strings.
-    /// They should not contain whitespaces and invalid characters for attributes or names.
-    pub fn from_name_and_values(name: String, values: Vec<String>) -> Option<Self> {
-        // TODO check on whitespaces.
-        Some(Attribute {
-            name,
-            values
-        })
+    /// Find the problem parsing fails on among the diagnostics that were not checked yet.
+    fn check_diagnostics(&mut self) {
+        let len = self.diagnostics.len();
+        let new = &mut self.diagnostics[self.checked..];
+        new.sort_by_key(|error| error.position.offset);
+        self.checked = len;
+        if self.error.is_none() {
+            let settings = self.settings;
+            self.error = new.iter().find(|error| settings.fails_on(error)).cloned();
+        }
     }
 
-    /// The name of the attribute.
-    pub fn name(&self) -> &str {
-        &self.name
+    /// Forget the code that was parsed after the first node was complete, along with its
+    /// problems.
+    fn keep_first_node(&mut self) {
+        let first = self.open[self.base - 1].children.first();
+        let end = match first.and_then(|first| first.span) {
+            Some(span) => span.end.offset,
+            None => return,
+        };
+        self.open.truncate(self.base);
+        self.diagnostics.retain(|error| error.position.offset < end);
+        self.checked = self.diagnostics.len();
+        if matches!(self.error, Some(ref error) if error.position.offset >= end) {
+            self.error = None;
+        }
+    }
+
+    /// Add the node that is represented by the event to the tree. The code of the event is
+    /// given in lossless mode.
+    fn process(&mut self, event: Event, span: Span, source: String) {
+        self.position = span.start;
+        match event {
+            Event::StartTag(mut tag) => {
+                self.close_implied_by(tag.name());
+                self.resolve_namespaces(&mut tag);
+                let self_closing = tag.is_self_closing() || tag.is_void();
+                let node = Node {
+                    start: Some(tag),
+                    end: None,
+                    text: None,
+                    raw_text: None,
+                    undecoded: false,
+                    children: Default::default(),
+                    markup: None,
+                    span: Some(span),
+                    end_span: None,
+                    raw_end: None,
+                    raw_markup: None,
+                    raw_before: self.take_dropped(),
+                };
+                if self_closing {
+                    // Void element has no content and is closed right after opening tag.
+                    self.append(node);
+                } else {
+                    self.open.push(node);
+                }
+            },
+            Event::EndTag(name) => self.close_by_end_tag(&name, span, source),
+            Event::Text { text, raw_text } => self.append_text(text, raw_text, span),
+            Event::Markup(markup) => self.append_markup(markup, span, source),
+        }
+        self.position = span.end;
+    }
+
+    /// Add the text node to the children of current element.
+    fn append_text(&mut self, text: String, raw_text: String, span: Span) {
+        let node = Node {
+            start: None,
+            end: None,
+            text: Some(text),
+            raw_text: Some(raw_text),
+            undecoded: !self.settings.decode_entities,
+            children: Default::default(),
+            markup: None,
+            span: Some(span),
+            end_span: None,
+            raw_end: None,
+            raw_markup: None,
+            raw_before: self.take_dropped(),
+        };
+        self.append(node);
+    }
+
+    /// Add the node with markup content to the children of current element.
+    fn append_markup(&mut self, markup: Markup, span: Span, source: String) {
+        let node = Node {
+            start: None,
+            end: None,
+            text: None,
+            raw_text: None,
+            undecoded: false,
+            children: Default::default(),
+            markup: Some(markup),
+            span: Some(span),
+            end_span: None,
+            raw_end: None,
+            raw_markup: if self.settings.lossless { Some(source) } else { None },
+            raw_before: self.take_dropped(),
+        };
+        self.append(node);
+    }
+
+    /// Keep the code that gives no node in lossless mode. It is added to the opening tag of
+    /// current element if the element has no content yet. Otherwise it is kept before the
+    /// next node or closing tag.
+    fn keep_dropped(&mut self, code: String) {
+        if code.is_empty() {
+            return;
+        }
+
+        let current = self.open.last_mut().unwrap();
+        if current.children.is_empty() {
+            let tag_end = current.start.as_mut().and_then(|start| start.raw_end.as_mut());
+            if let Some(tag_end) = tag_end {
+                *tag_end += &code;
+                return;
+            }
+        }
+        self.dropped += &code;
+    }
+
+    /// Take the code that waits to be kept before the next node or closing tag.
+    fn take_dropped(&mut self) -> Option<String> {
+        if self.dropped.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.dropped))
+        }
+    }
+
+    /// Whether the first node of the tree is already fully read.
+    fn has_first_node(&self) -> bool {
+        !self.open[self.base - 1].children.is_empty()
+    }
+
+    /// Close all elements that are still open and return the root node with all diagnostics.
+    /// The problem parsing stopped on is returned as an error instead.
+    fn finish(mut self) -> Result<(Node, Vec<Error>), Error> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        // Closing tags are often omitted at the end of the code so this is not an error even
+        // in strict mode.
+        while self.open.len() > self.base {
+            self.close_current(None);
+        }
+        // Code after the last node is written at the end of the whole tree.
+        self.open[0].raw_end = self.take_dropped();
+        if self.base > 1 {
+            // Content of the context element is the content of the fragment.
+            let context = self.open.pop().unwrap();
+            self.open[0].children = context.children;
+        }
+
+        self.diagnostics.sort_by_key(|error| error.position.offset);
+        Ok((self.open.pop().unwrap(), self.diagnostics))
+    }
+
+    /// Find the namespace of the element which is going to be added to the current one and
+    /// the namespaces of its attributes.
+    fn resolve_namespaces(&self, tag: &mut OpeningTag) {
+        let parent_namespace = self.content_namespace();
+
+        tag.namespace = if let Some(xmlns) = tag.attribute_by_name("xmlns") {
+            Namespace::from_uri(&xmlns.values_to_string())
+        } else if is_one_of(&tag.name, &["svg"]) {
+            Namespace::Svg
+        } else if is_one_of(&tag.name, &["math"]) {
+            Namespace::MathMl
+        } else {
+            parent_namespace
+        };
+        if tag.namespace == Namespace::Html {
+            // Attributes of HTML elements have no namespaces.
+            return;
+        }
+
+        let namespaces: Vec<_> = tag.attrs.iter().map(|attr| match attr.prefix() {
+            Some("xlink") => Some(Namespace::XLink),
+            Some("xml") => Some(Namespace::Xml),
+            Some("xmlns") => Some(Namespace::Xmlns),
+            None if attr.name == "xmlns" => Some(Namespace::Xmlns),
+            None => None,
+            Some(prefix) => {
+                // Prefix is declared in this element or in one of its parents.
+                let declaration = format!("xmlns:{}", prefix);
+                let parents = self.open.iter().rev().filter_map(|node| node.start.as_ref());
+                std::iter::once(&*tag).chain(parents)
+                    .find_map(|tag| tag.attribute_by_name(&declaration))
+                    .map(|attr| Namespace::from_uri(&attr.values_to_string()))
+            },
+        }).collect();
+        for (attr, namespace) in tag.attrs.iter_mut().zip(namespaces) {
+            attr.namespace = namespace;
+        }
+    }
+
+    /// Namespace of the content of current element.
+    fn content_namespace(&self) -> Namespace {
+        match self.open.last().unwrap().start {
+            Some(ref start) if !is_one_of(start.name(), HTML_INTEGRATION_POINTS) => {
+                start.namespace.clone()
+            },
+            _ => Namespace::Html,
+        }
+    }
+
+    /// Remember the problem found at given position.
+    fn report(&mut self, kind: ErrorKind, position: Position) {
+        self.diagnostics.push(Error::new(kind, position));
+    }
+
+    /// Add complete node to the children of current element.
+    fn append(&mut self, node: Node) {
+        let node = NodeAccess::new(node, &self.settings.children_type);
+        self.open.last_mut().unwrap().children.push(node);
+    }
+
+    /// Close current element with given closing tag and move it to the children of its parent.
+    fn close_current(&mut self, end: Option<(String, Span)>) {
+        let mut node = self.open.pop().unwrap();
+        if end.is_none() {
+            let name = node.tag_name().unwrap_or("");
+            if !is_one_of(name, IMPLIED_END_ELEMENTS) && !is_one_of(name, OPTIONAL_END_ELEMENTS) {
+                let kind = ErrorKind::UnclosedElement(String::from(name));
+                self.report(kind, self.position);
+            }
+        }
+
+        // Element without closing tag ends where its last child ends.
+        let end_pos = match end {
+            Some((_, ref span)) => Some(span.end),
+            None => node.children.last().and_then(|child| child.span).map(|span| span.end),
+        };
+        if let (Some(span), Some(end_pos)) = (node.span.as_mut(), end_pos) {
+            span.end = end_pos;
+        }
+        if let Some((name, span)) = end {
+            node.end = Some(name);
+            node.end_span = Some(span);
+        }
+
+        // Text which is not mixed with children can be stored in the node itself.
+        if !self.settings.all_text_separately && node.children.len() == 1 {
+            let child = node.children.first().unwrap();
+            if child.start.is_none() && child.text.is_some() && child.raw_before.is_none() {
+                let child = node.children.pop().unwrap();
+                node.text = child.text.clone();
+                node.raw_text = child.raw_text.clone();
+                node.undecoded = child.undecoded;
+            }
+        }
+
+        self.append(node);
+    }
+
+    /// Name of the element at given index in the stack of open elements.
+    fn name_at(&self, i: usize) -> &str {
+        self.open[i].tag_name().unwrap_or("")
+    }
+
+    fn current_name(&self) -> &str {
+        self.name_at(self.open.len() - 1)
+    }
+
+    /// Find open element with one of given names. Search stops on scope boundary elements and
+    /// on elements from `boundaries` list.
+    fn find_in_scope(&self, names: &[&str], boundaries: &[&str]) -> Option<usize> {
+        for i in (self.base..self.open.len()).rev() {
+            let name = self.name_at(i);
+            if is_one_of(name, names) {
+                return Some(i);
+            } else if is_one_of(name, SCOPE_ELEMENTS) || is_one_of(name, boundaries) {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Close all elements starting from the one at given index to the current one.
+    fn close_to(&mut self, i: usize) {
+        while self.open.len() > i {
+            self.close_current(None);
+        }
+    }
+
+    /// Close the paragraph if it is open in button scope.
+    fn close_p(&mut self) {
+        if let Some(i) = self.find_in_scope(&["p"], &["button"]) {
+            self.close_to(i);
+        }
+    }
+
+    /// Close the elements that cannot contain the element with given name. Such elements
+    /// get closed implicitly when this element is opened.
+    fn close_implied_by(&mut self, name: &str) {
+        let name = name.to_ascii_lowercase();
+        let name = name.as_str();
+
+        if is_one_of(name, CLOSE_P_ELEMENTS) || is_one_of(name, &["li", "dd", "dt"]) {
+            self.close_p();
+        }
+
+        match name {
+            "li" => self.close_list_item(&["li"]),
+            "dd" | "dt" => self.close_list_item(&["dd", "dt"]),
+            "option" if is_one_of(self.current_name(), &["option"]) => {
+                self.close_current(None);
+            },
+            "optgroup" => {
+                if is_one_of(self.current_name(), &["option"]) {
+                    self.close_current(None);
+                }
+                if is_one_of(self.current_name(), &["optgroup"]) {
+                    self.close_current(None);
+                }
+            },
+            "td" | "th" => self.close_table_cell(),
+            "tr" => {
+                self.close_table_cell();
+                if let Some(i) = self.find_in_table_scope(&["tr"]) {
+                    self.close_to(i);
+                }
+            },
+            "tbody" | "tfoot" | "thead" => {
+                self.close_table_cell();
+                if let Some(i) = self.find_in_table_scope(TABLE_SECTION_ELEMENTS) {
+                    self.close_to(i);
+                }
+            },
+            "rb" | "rtc" if self.find_in_scope(&["ruby"], &[]).is_some() => {
+                self.close_implied_except(None);
+            },
+            "rp" | "rt" if self.find_in_scope(&["ruby"], &[]).is_some() => {
+                self.close_implied_except(Some("rtc"));
+            },
+            _ if is_one_of(name, HEADING_ELEMENTS)
+                    && is_one_of(self.current_name(), HEADING_ELEMENTS) => {
+                self.close_current(None);
+            },
+            _ => (),
+        }
+    }
+
+    /// Close the list item with one of given names that is still open.
+    fn close_list_item(&mut self, names: &[&str]) {
+        for i in (self.base..self.open.len()).rev() {
+            let name = self.name_at(i);
+            if is_one_of(name, names) {
+                self.close_to(i);
+                return;
+            } else if is_one_of(name, SPECIAL_ELEMENTS)
+                    && !is_one_of(name, &["address", "div", "p"]) {
+                return;
+            }
+        }
+    }
+
+    fn find_in_table_scope(&self, names: &[&str]) -> Option<usize> {
+        for i in (self.base..self.open.len()).rev() {
+            let name = self.name_at(i);
+            if is_one_of(name, names) {
+                return Some(i);
+            } else if is_one_of(name, &["html", "table", "template"]) {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn close_table_cell(&mut self) {
+        if let Some(i) = self.find_in_table_scope(TABLE_CELL_ELEMENTS) {
+            self.close_to(i);
+        }
+    }
+
+    /// Close all current elements which closing tags can be omitted, except the one with
+    /// given name.
+    fn close_implied_except(&mut self, except: Option<&str>) {
+        while self.open.len() > self.base {
+            let name = self.current_name();
+            if !is_one_of(name, IMPLIED_END_ELEMENTS) || Some(name) == except {
+                break;
+            }
+            self.close_current(None);
+        }
+    }
+
+    /// Close the element by its closing tag. All elements opened inside of it are closed too.
+    /// If there is no such element open the closing tag is ignored.
+    fn close_by_end_tag(&mut self, name: &str, span: Span, source: String) {
+        if is_void_element(name.as_bytes()) {
+            // Void elements cannot have closing tags. Ignore them if they are present anyway.
+            self.report(ErrorKind::UnmatchedEndTag(String::from(name)), span.start);
+            self.keep_dropped(source);
+            return;
+        }
+
+        let found = if is_one_of(name, SPECIAL_ELEMENTS) {
+            // Special element is closed only if it is in the scope. Elements which closing tags
+            // were omitted are closed too.
+            if is_one_of(name, TABLE_ELEMENTS) {
+                self.find_in_table_scope(&[name])
+            } else if is_one_of(name, HEADING_ELEMENTS) {
+                self.find_in_scope(HEADING_ELEMENTS, &[])
+            } else if is_one_of(name, &["li"]) {
+                self.find_in_scope(&[name], &["ol", "ul"])
+            } else if is_one_of(name, &["p"]) {
+                self.find_in_scope(&[name], &["button"])
+            } else {
+                self.find_in_scope(&[name], &[])
+            }
+        } else {
+            // Other elements are searched until the first special element.
+            let mut found = None;
+            for i in (self.base..self.open.len()).rev() {
+                let open = self.name_at(i);
+                if open.eq_ignore_ascii_case(name) {
+                    found = Some(i);
+                    break;
+                } else if is_one_of(open, SPECIAL_ELEMENTS) {
+                    break;
+                }
+            }
+            found
+        };
+
+        if let Some(i) = found {
+            self.close_to(i + 1);
+            let own_name = self.name_at(i);
+            let end = if own_name.eq_ignore_ascii_case(name) {
+                String::from(name)
+            } else {
+                // Heading closed by the closing tag of another heading keeps its own name.
+                let own_name = String::from(own_name);
+                self.report(ErrorKind::UnmatchedEndTag(String::from(name)), span.start);
+                own_name
+            };
+            if self.settings.lossless {
+                let dropped = self.take_dropped().unwrap_or_default();
+                self.open[i].raw_end = Some(dropped + &source);
+            }
+            self.close_current(Some((end, span)));
+        } else if name.eq_ignore_ascii_case("p") {
+            // Stray `</p>` closes an empty paragraph like in browsers.
+            self.report(ErrorKind::UnmatchedEndTag(String::from(name)), span.start);
+            let empty = Span::new(span.start, span.start);
+            let mut tag = OpeningTag::from_token(name, &[], false, empty);
+            if self.settings.lossless {
+                tag.raw_start = Some(String::new());
+                tag.raw_end = Some(String::new());
+            }
+            let node = Node {
+                start: Some(tag),
+                span: Some(empty),
+                raw_end: if self.settings.lossless { Some(source) } else { None },
+                raw_before: self.take_dropped(),
+                ..Default::default()
+            };
+            self.open.push(node);
+            self.close_current(Some((String::from(name), span)));
+        } else {
+            self.report(ErrorKind::UnmatchedEndTag(String::from(name)), span.start);
+            self.keep_dropped(source);
+        }
+    }
+}
+
+impl<'a> ChildrenFetch<'a> {
+
+    /// Get children fetcher for given node to find children that apply to some criteria.
+    pub fn for_node(node: &'a Node) -> Self {
+        ChildrenFetch {
+            node,
+            tag:        None,
+            key:        None,
+            value:      None,
+            value_part: None,
+            comment:    None,
+            namespace:  None,
+        }
+    }
+
+    /// Clone the fetcher with already set criteria but for given different node.
+    pub fn same_for_node(&self, node: &'a Node) -> Self {
+        let mut new = self.clone();
+        new.node = node;
+        new
+    }
+
+    /// Tag to search for.
+    pub fn tag(mut self, tag: &'a str) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    pub fn set_tag(&mut self, tag: &'a str) {
+        self.tag = Some(tag);
+    }
+
+    /// Key to search for.
+    pub fn key(mut self, key: &'a str) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn set_key(&mut self, key: &'a str) {
+        self.key = Some(key);
+    }
+
+    /// Exact value to search for. The value with its parts separated by single spaces
+    /// matches too.
+    pub fn value(mut self, value: &'a str) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    pub fn set_value(&mut self, value: &'a str) {
+        self.value = Some(value);
+    }
+
+    /// If exact value is not set then this defines a part of the value separated with whitespaces
+    /// to be found. If `value` is, however, set then this field is ignored entirely.
+    pub fn value_part(mut self, part: &'a str) -> Self {
+        self.value_part = Some(part);
+        self
+    }
+
+    pub fn set_value_part(&mut self, part: &'a str) {
+        self.value_part = Some(part);
+    }
+
+    /// Search for comments with given text. Whitespaces around the comment text are ignored.
+    /// Other criteria do not apply to comments.
+    pub fn comment(mut self, comment: &'a str) -> Self {
+        self.comment = Some(comment);
+        self
+    }
+
+    pub fn set_comment(&mut self, comment: &'a str) {
+        self.comment = Some(comment);
+    }
+
+    /// Namespace of the elements to search for, e.g. to find only SVG elements.
+    pub fn namespace(mut self, namespace: &'a Namespace) -> Self {
+        self.namespace = Some(namespace);
+        self
+    }
+
+    pub fn set_namespace(&mut self, namespace: &'a Namespace) {
+        self.namespace = Some(namespace);
+    }
+
+    /// Get all children and their children that apply to the criteria.
+    /// This function does not check the parent node!
+    pub fn fetch(self) -> LinkedList<&'a NodeAccess> {
+        fn sub(criteria: ChildrenFetch) -> LinkedList<&NodeAccess> {
+            let mut list = LinkedList::new();
+
+            for child in criteria.node.children.iter() {
+                // Comments are searched only by their text.
+                if let Some(comment) = criteria.comment {
+                    if child.comment().map(|s| s.trim()) == Some(comment) {
+                        list.push_back(child);
+                    }
+
+                    let new_fetch = criteria.same_for_node(child);
+                    let mut nodes = sub(new_fetch);
+                    list.append(&mut nodes);
+                    continue;
+                }
+
+                // Filter on tag if present. Children of other elements are searched anyway.
+                let tag_matches = criteria.tag.map_or(true, |tag| {
+                    child.tag_name().unwrap_or("").eq_ignore_ascii_case(tag)
+                });
+                // Filter value and value_part by criteria. Append filtered values to list.
+                let mut check_value_criteria = |attr: &Attribute| {
+                    if let Some(value) = criteria.value {
+                        // Values written with other whitespace still match by their parts.
+                    if attr.value() == value || attr.values.join(" ") == value {
+                            list.push_back(child);
+                        }
+                    } else if let Some(part) = criteria.value_part {
+                        if attr.tokens().any(|i| i == part) {
+                            list.push_back(child);
+                        }
+                    } else {
+                        // No value expected and finding of a key is enough.
+                        list.push_back(child);
+                    }
+                };
+
+                // Children of the element in other namespace can still be in the namespace.
+                let namespace = child.start.as_ref().map(|start| &start.namespace);
+                if !tag_matches
+                    || (criteria.namespace.is_some() && namespace != criteria.namespace)
+                {
+                    // Skip the element itself.
+                } else if let Some(key) = criteria.key {
+                    if let Some(attr) = child.attribute_by_name(key) {
+                        check_value_criteria(attr)
+                    }
+                } else if criteria.namespace.is_some() && criteria.value.is_none()
+                    && criteria.value_part.is_none()
+                {
+                    // Namespace is enough to find the element, even the one without attributes.
+                    list.push_back(child);
+                } else {
+                    if let Some(attrs) = child.attributes() {
+                        for attr in attrs {
+                            check_value_criteria(attr)
+                        }
+                    }
+                }
+
+                let new_fetch = criteria.same_for_node(&child);
+                let mut nodes = sub(new_fetch);
+                list.append(&mut nodes);
+            }
+
+            list
+        }
+
+        sub(self)
+    }
+}
+
+impl<'a> ChildrenFetchMut<'a> {
+
+    /// Get children fetcher for given node to find children that apply to some criteria.
+    pub fn for_node(node: &'a Node) -> Self {
+        let inner = ChildrenFetch {
+            node,
+            tag:        None,
+            key:        None,
+            value:      None,
+            value_part: None,
+            comment:    None,
+            namespace:  None,
+        };
+        ChildrenFetchMut { inner }
+    }
+
+    /// Get all children and their children that apply to the criteria.
+    pub fn fetch_mut(self) -> LinkedList<&'a mut NodeAccess> {
+        let fetch = self.fetch();
+        let mut result = LinkedList::new();
+        for i in fetch {
+            let a = i as *const NodeAccess as *mut NodeAccess;
+            let a = unsafe { &mut *a };
+            result.push_back(a);
+        }
+        result
+    }
+
+    pub fn fetch(self) -> LinkedList<&'a NodeAccess> {
+        self.inner.fetch()
+    }
+
+    /// Clone the fetcher with already set criteria but for given different node.
+    pub fn same_for_node(&self, node: &'a Node) -> Self {
+        ChildrenFetchMut { inner: self.inner.same_for_node(node) }
+    }
+
+    /// Key to search for.
+    pub fn key(self, key: &'a str) -> Self {
+        let inner = self.inner.key(key);
+        ChildrenFetchMut { inner }
+    }
+
+    /// Exact value to search for. The value with its parts separated by single spaces
+    /// matches too.
+    pub fn value(self, value: &'a str) -> Self {
+        let inner = self.inner.value(value);
+        ChildrenFetchMut { inner }
+    }
+
+    /// If exact value is not set then this defines a part of the value separated with whitespaces
+    /// to be found. If `value` is, however, set then this field is ignored entirely.
+    pub fn value_part(self, part: &'a str) -> Self {
+        let inner = self.inner.value_part(part);
+        ChildrenFetchMut { inner }
+    }
+
+    /// Search for comments with given text. Whitespaces around the comment text are ignored.
+    pub fn comment(self, comment: &'a str) -> Self {
+        let inner = self.inner.comment(comment);
+        ChildrenFetchMut { inner }
+    }
+
+    /// Namespace of the elements to search for.
+    pub fn namespace(self, namespace: &'a Namespace) -> Self {
+        let inner = self.inner.namespace(namespace);
+        ChildrenFetchMut { inner }
+    }
+}
+
+impl OpeningTag {
+
+    /// Create opening tag from the token.
+    fn from_token(name: &str, attrs: &[TokenAttribute], empty: bool, span: Span) -> Self {
+        let attrs = attrs.iter().map(|attr| {
+            match attr.value {
+                Some(ref value) => {
+                    let value = entities::decode(value, true);
+                    Attribute::from_name_and_str_values(attr.name.clone(), &value)
+                },
+                None => Attribute::from_name(attr.name.clone()),
+            }
+        }).collect();
+
+        OpeningTag {
+            empty,
+            name: String::from(name),
+            attrs,
+            span: Some(span),
+            namespace: Namespace::Html,
+            raw_start: None,
+            raw_end: None,
+            raw_dropped: Vec::new(),
+        }
+    }
+
+    /// Keep the code of the tag read from given token so that it is written back as it was.
+    fn keep_source(&mut self, source: &str, attrs: &[TokenAttribute]) {
+        let name_end = 1 + self.name.len();
+        let attrs_end = attrs.last().map(|attr| attr.source.end).unwrap_or(name_end);
+        self.raw_start = Some(String::from(&source[..name_end]));
+        self.raw_end = Some(String::from(&source[attrs_end..]));
+        for (attr, token) in self.attrs.iter_mut().zip(attrs) {
+            attr.raw = Some(String::from(&source[token.source.clone()]));
+        }
+    }
+
+    /// Apply the policy to the attributes with the same names. Returns the index of every
+    /// repeated attribute among the attributes the tag was read with. Code of the dropped
+    /// attributes is kept in lossless mode.
+    fn resolve_duplicates(&mut self, policy: DuplicateAttributes) -> Vec<usize> {
+        let mut duplicates = Vec::new();
+        let mut indices: Vec<_> = (0..self.attrs.len()).collect();
+        let mut i = 0;
+        while i < self.attrs.len() {
+            let name = &self.attrs[i].name;
+            let first = self.attrs[..i].iter()
+                .position(|attr| attr.name.eq_ignore_ascii_case(name));
+            let first = match first {
+                Some(first) => first,
+                None => {
+                    i += 1;
+                    continue;
+                },
+            };
+            duplicates.push(indices[i]);
+
+            let dropped = match policy {
+                DuplicateAttributes::FirstWins => i,
+                DuplicateAttributes::LastWins => first,
+                DuplicateAttributes::KeepAll | DuplicateAttributes::Error => {
+                    i += 1;
+                    continue;
+                },
+            };
+            let attr = self.attrs.remove(dropped);
+            indices.remove(dropped);
+            if let Some(raw) = attr.raw {
+                // Dropped code is kept in the order it was written: after the code kept
+                // before the same attribute and before the code kept before the next ones.
+                let at = self.raw_dropped.iter().position(|&(next, _)| next > dropped)
+                    .unwrap_or(self.raw_dropped.len());
+                self.raw_dropped[at..].iter_mut().for_each(|(next, _)| *next -= 1);
+                self.raw_dropped.insert(at, (dropped, raw));
+            }
+        }
+        duplicates
+    }
+
+    /// Write the code of the dropped attributes that is before the attribute with given index.
+    fn write_dropped(&self, s: &mut String, index: usize) {
+        for (_, raw) in self.raw_dropped.iter().filter(|&&(next, _)| next == index) {
+            *s += raw;
+        }
+    }
+
+    /// Name of this tag.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Attributes of tag.
+    pub fn attributes(&self) -> &Vec<Attribute> {
+        &self.attrs
+    }
+
+    /// Find attribute by it's name. Names are compared case-insensitively.
+    pub fn attribute_by_name(&self, key: &str) -> Option<&Attribute> {
+        self.attrs.iter().find(|attr| attr.name().eq_ignore_ascii_case(key))
+    }
+
+    pub fn is_self_closing(&self) -> bool {
+        self.empty
+    }
+
+    /// Whether this is a void element like `<br>` or `<img>`. Such element never has
+    /// any children nor closing tag.
+    pub fn is_void(&self) -> bool {
+        is_void_element(self.name.as_bytes())
+    }
+
+    /// Part of the code with this tag. None if the tag was not loaded from the code.
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Namespace of the element. Elements inside `<svg>` and `<math>` are in SVG and MathML
+    /// namespaces. Namespace can also be set with `xmlns` attribute.
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+}
+
+/// Check whether the name is in the list of element names.
+fn is_one_of(name: &str, list: &[&str]) -> bool {
+    list.iter().any(|s| s.eq_ignore_ascii_case(name))
+}
+
+/// Check whether the tag with given name is a void element.
+fn is_void_element(name: &[u8]) -> bool {
+    VOID_ELEMENTS.iter().any(|void| void.as_bytes().eq_ignore_ascii_case(name))
+}
+
+/// Check whether the attribute with given name is read back from the code with the same name.
+fn is_valid_attribute_name(name: &str) -> bool {
+    // Name can start with '=', see `Tokenizer::read_name`.
+    !name.is_empty()
+        && !name.bytes().skip(1).any(|b| b == b'=')
+        && !name.bytes().any(|b| b.is_ascii_whitespace() || b == b'/' || b == b'>')
+}
+
+/// Break the closing tags of the raw text element with given name in its text with a
+/// backslash, e.g. `</script>` becomes `<\/script>`, so the text cannot end the element.
+fn escape_raw_text<'a>(text: &'a str, element: &str) -> Cow<'a, str> {
+    let is_end = |rest: &[u8]| {
+        rest.len() >= element.len() + 2
+            && rest[2..element.len() + 2].eq_ignore_ascii_case(element.as_bytes())
+    };
+    let bytes = text.as_bytes();
+    let mut ends = memchr_iter(b'<', bytes).filter(|&i| bytes.get(i + 1) == Some(&b'/'))
+        .filter(|&i| is_end(&bytes[i..])).peekable();
+    if ends.peek().is_none() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut s = String::with_capacity(text.len() + 8);
+    let mut from = 0;
+    for i in ends {
+        s += &text[from..i + 1];
+        s += "\\";
+        from = i + 1;
+    }
+    s += &text[from..];
+    Cow::Owned(s)
+}
+
+impl Attribute {
+
+    /// Create from a name and the value passed as single string. The value is kept as it is,
+    /// whitespaces in it are not changed.
+    pub fn from_name_and_str_values(name: String, values: &str) -> Self {
+        Self::with_value(name, Some(String::from(values)))
+    }
+
+    /// Create attribute without a value like `disabled` in `<input disabled>`.
+    pub fn from_name(name: String) -> Self {
+        Self::with_value(name, None)
+    }
+
+    fn with_value(name: String, value: Option<String>) -> Self {
+        let mut attr = Attribute {
+            name,
+            value: None,
+            values: Vec::new(),
+            namespace: None,
+            raw: None,
+        };
+        attr.store(value);
+        attr
+    }
+
+    /// Create from a name and values passed as array of strings. The value of the attribute
+    /// is these strings separated with spaces.
+    /// They should not contain whitespaces and invalid characters for attributes or names.
+    pub fn from_name_and_values(name: String, values: Vec<String>) -> Option<Self> {
+        // TODO check on whitespaces.
+        Some(Self::with_value(name, Some(values.join(" "))))
+    }
+
+    /// The name of the attribute.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Prefix of the name like `xlink` in `xlink:href`.
+    pub fn prefix(&self) -> Option<&str> {
+        self.name.find(':').map(|i| &self.name[..i])
+    }
+
+    /// Name without the prefix like `href` in `xlink:href`.
+    pub fn local_name(&self) -> &str {
+        self.name.find(':').map(|i| &self.name[i + 1..]).unwrap_or(&self.name)
+    }
+
+    /// Namespace of the attribute. Only prefixed attributes of SVG and MathML elements have
+    /// namespaces.
+    pub fn namespace(&self) -> Option<&Namespace> {
+        self.namespace.as_ref()
+    }
+
+    /// The value of the attribute exactly as it was in the code with decoded character
+    /// references. To get the values separated with whitespaces, use [`tokens`].
+    /// Attributes without value have empty one.
+    pub fn value(&self) -> &str {
+        self.value.as_deref().unwrap_or("")
+    }
+
+    /// Whether the attribute has a value. It has none when it is written only with its name
+    /// like `<input disabled>`. Note that `disabled=""` has an empty value.
+    pub fn has_value(&self) -> bool {
+        self.value.is_some()
+    }
+
+    /// Values separated with whitespaces like classes in `class` attribute. The value itself
+    /// is not changed, this is only a view of it.
+    pub fn tokens(&self) -> std::str::SplitAsciiWhitespace<'_> {
+        self.value().split_ascii_whitespace()
     }
 
     /// All values stored in the attribute. Each value separated with whitespace is
     /// located in another string in the array. To get values as single string, use
     /// [`values_to_string`]
+    #[deprecated(note = "use `tokens` to get the values or `value` to get the whole value")]
     pub fn values(&self) -> &Vec<String> {
         &self.values
     }
 
-    /// Store all values in a string separated with spaces.
-    pub fn values_to_string(&self) -> String {
-        // Calculate the length of the string to allocate.
-        let len = {
-            let mut l = 0;
-            for val in &self.values {
-                l += val.len() + 1; // For space at the end.
-            }
-            if l == 0 {
-                // There are no values - empty string.
-                return String::new();
-            }
-            l - 1 // Remove trailing last space.
-        };
+    /// Copy the value of the attribute into new string. See [`value`].
+    pub fn values_to_string(&self) -> String {
+        String::from(self.value())
+    }
+
+    /// Get first value of the attribute if any.
+    ///
+    /// Usually, when attribute is known to contain single value this function makes it easier
+    /// to obtain this value. It does not construct new string as `values_to_string` and
+    /// is shorter than calling `tokens` and getting first value manually (but is equivalent).
+    ///
+    /// # Panics
+    /// This function will panic if there are no attribute values.
+    pub fn first_value(&self) -> &String {
+        self.values.first().unwrap()
+    }
+
+    /// Set new name for attribute.
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+        self.raw = None;
+    }
+
+    /// Set new values for attribute. They are stored separated with spaces. If any of passed
+    /// strings have whitespaces then this function will fail.
+    pub fn set_values(&mut self, values: Vec<String>) -> Result<(), ()> {
+        // Check strings
+        for s in &values {
+            if s.split_ascii_whitespace().count() > 1 {
+                return Err(());
+            }
+        }
+
+        self.store(Some(values.join(" ")));
+
+        Ok(())
+    }
+
+    /// Set new value for attribute. It is stored exactly as it is.
+    pub fn set_value(&mut self, value: String) {
+        self.store(Some(value));
+    }
+
+    /// Remove the value so that the attribute is written only with its name.
+    pub fn remove_value(&mut self) {
+        self.store(None);
+    }
+
+    /// Set values from string. The string is stored exactly as it is so this never fails.
+    #[deprecated(note = "use `set_value` which does not return the result")]
+    pub fn set_values_from_str(&mut self, values: &str) -> Result<(), ()> {
+        self.set_value(String::from(values));
+        Ok(())
+    }
+
+    /// Replace the value along with its parts. The code of the attribute no longer matches
+    /// it.
+    fn store(&mut self, value: Option<String>) {
+        self.values = value.iter().flat_map(|value| value.split_ascii_whitespace())
+            .map(String::from)
+            .collect();
+        self.value = value;
+        self.raw = None;
+    }
+}
+
+impl Error {
+
+    /// Create error of given kind that occurred at given position of the code.
+    fn new(kind: ErrorKind, position: Position) -> Self {
+        Error { kind, position }
+    }
+
+    /// The reason of the error.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Location in the code where the error occurred.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+}
+
+impl std::fmt::Display for Error {
+
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at line {}, column {}", self.kind, self.position.line,
+            self.position.column)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for ErrorKind {
+
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use ErrorKind::*;
+        match self {
+            UnexpectedEof(s) => write!(f, "unexpected end of code while reading {}", s),
+            UnexpectedToken(s) => write!(f, "unexpected token '{}'", s),
+            UnexpectedBang => write!(f, "only comment, CDATA and DOCTYPE can start with '<!'"),
+            UnmatchedEndTag(s) => write!(f, "closing tag '{}' has no opened element", s),
+            UnclosedElement(s) => write!(f, "element '{}' is not closed", s),
+            DuplicateAttribute(s, policy) => {
+                write!(f, "attribute '{}' is repeated", s)?;
+                match policy {
+                    DuplicateAttributes::FirstWins => write!(f, ", the first one is used"),
+                    DuplicateAttributes::LastWins => write!(f, ", the last one is used"),
+                    DuplicateAttributes::KeepAll => write!(f, ", all of them are kept"),
+                    DuplicateAttributes::Error => Ok(()),
+                }
+            },
+            Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Position {
+
+    /// Position of the beginning of the code.
+    fn code_start() -> Self {
+        Position {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    /// Position after given text that starts at this position.
+    fn advanced(self, text: &str) -> Self {
+        let bytes = text.as_bytes();
+        let lines = memchr_iter(b'\n', bytes).count();
+        let column = match memchr::memrchr(b'\n', bytes) {
+            Some(i) => text[i + 1..].chars().count() + 1,
+            None => self.column + text.chars().count(),
+        };
+
+        Position {
+            offset: self.offset + text.len(),
+            line: self.line + lines,
+            column,
+        }
+    }
+
+    /// Offset in bytes from the beginning of the code.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Line number starting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
 
-        let mut s = String::with_capacity(len);
+    /// Column number in characters starting from 1.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
 
-        let mut i = 0;
-        while i < self.values.len() {
-            s += self.values.get(i).unwrap();
+impl Span {
 
-            i += 1;
-            // Do not add last (trailing) space.
-            if i < self.values.len() {
-                s += " ";
-            }
-        }
+    fn new(start: Position, end: Position) -> Self {
+        Span { start, end }
+    }
 
-        s
+    /// Position of the first character.
+    pub fn start(&self) -> Position {
+        self.start
     }
 
-    /// Get first value of the attribute if any.
-    ///
-    /// Usually, when attribute is known to contain single value this function makes it easier
-    /// to obtain this value. It does not construct new string as `values_to_string` and
-    /// is shorter than calling `values` and gettings first value manually (but is equivalent).
-    ///
-    /// # Panics
-    /// This function will panic if there are no attribute values.
-    pub fn first_value(&self) -> &String {
-        self.values.get(0).unwrap()
+    /// Position right after the last character.
+    pub fn end(&self) -> Position {
+        self.end
     }
 
-    /// Set new name for attribute.
-    pub fn set_name(&mut self, name: String) {
-        self.name = name;
+    /// Range of bytes in the code.
+    pub fn range(&self) -> Range<usize> {
+        self.start.offset..self.end.offset
     }
+}
 
-    /// Set new values for attribute. If any of passed strings have whitespaces then this
-    /// function will fail.
-    pub fn set_values(&mut self, values: Vec<String>) -> Result<(), ()> {
-        // Check strings
-        for s in &values {
-            if s.split_whitespace().count() > 1 {
-                return Err(());
-            }
-        }
+impl Namespace {
 
-        self.values = values;
+    /// Namespace with given URI.
+    pub fn from_uri(uri: &str) -> Self {
+        use Namespace::*;
+        [Html, Svg, MathMl, XLink, Xml, Xmlns].iter()
+            .find(|namespace| namespace.uri() == uri)
+            .cloned()
+            .unwrap_or_else(|| Other(String::from(uri)))
+    }
 
-        Ok(())
+    /// URI of the namespace.
+    pub fn uri(&self) -> &str {
+        match self {
+            Namespace::Html => "http://www.w3.org/1999/xhtml",
+            Namespace::Svg => "http://www.w3.org/2000/svg",
+            Namespace::MathMl => "http://www.w3.org/1998/Math/MathML",
+            Namespace::XLink => "http://www.w3.org/1999/xlink",
+            Namespace::Xml => "http://www.w3.org/XML/1998/namespace",
+            Namespace::Xmlns => "http://www.w3.org/2000/xmlns/",
+            Namespace::Other(uri) => uri,
+        }
     }
+}
 
-    /// Set values from string.
-    pub fn set_values_from_str(&mut self, values: &str) -> Result<(), ()> {
-        let split = values.split_whitespace();
-        let vec: Vec<&str> = split.collect();
-        let mut new_vec = Vec::with_capacity(vec.len());
-        for i in vec {
-            new_vec.push(i.to_string());
+impl Whitespace {
+
+    /// Apply the policy to the text with given span. Returns the changed text and its span.
+    /// None is returned if the text should be dropped.
+    fn apply(self, text: String, span: Span) -> Option<(String, Span)> {
+        let is_empty = text.bytes().all(|b| b.is_ascii_whitespace());
+        match self {
+            Whitespace::Trim => Self::trim(&text, span),
+            Whitespace::Preserve => Some((text, span)),
+            Whitespace::DropEmpty if is_empty => None,
+            Whitespace::DropEmpty => Some((text, span)),
+            Whitespace::Collapse => {
+                let mut collapsed = String::with_capacity(text.len());
+                let mut space = false;
+                for c in text.chars() {
+                    if !c.is_ascii_whitespace() {
+                        collapsed.push(c);
+                        space = false;
+                    } else if !space {
+                        collapsed.push(' ');
+                        space = true;
+                    }
+                }
+                Some((collapsed, span))
+            },
         }
+    }
+
+    /// Remove empty text on newlines around the text. Returns trimmed text and its span. None
+    /// is returned if nothing is left of the text.
+    fn trim(text: &str, span: Span) -> Option<(String, Span)> {
+        let trim_start = |s: &str| -> usize {
+            if s.is_empty() {
+                return 0;
+            }
+
+            let mut iter = s.chars();
+            let first = iter.next().unwrap();
+            let trimmed = s.len() - s.trim_start().len();
+            if first == '\n' {
+                trimmed
+            } else if first == '\t' || first == ' ' {
+                for ch in iter {
+                    if ch != '\t' && ch != ' ' && ch != '\n' {
+                        return 0;
+                    }
+                }
+                trimmed
+            } else {
+                0
+            }
+        };
+        let trim_end = |s: &str| -> usize {
+            if memchr::memchr(b'\n', s.as_bytes()).is_some() {
+                s.trim_end().len()
+            } else {
+                s.len()
+            }
+        };
 
-        self.set_values(new_vec)
+        let start = trim_start(text);
+        let len = trim_end(&text[start..]);
+        if len != 0 {
+            let s = &text[start..start + len];
+            let start = span.start.advanced(&text[..start]);
+            let span = Span::new(start, start.advanced(s));
+            Some((String::from(s), span))
+        } else {
+            None
+        }
     }
 }
 
@@ -1273,6 +2847,14 @@ impl Default for LoadSettings {
         LoadSettings {
             all_text_separately: true,
             children_type: ChildrenType::Owned,
+            skip_comments: false,
+            decode_entities: true,
+            mode: ParseMode::Strict,
+            whitespace: Whitespace::Trim,
+            keep_foreign_case: false,
+            default_encoding: encoding_rs::WINDOWS_1252,
+            lossless: false,
+            duplicate_attributes: DuplicateAttributes::FirstWins,
         }
     }
 }
@@ -1308,6 +2890,146 @@ impl LoadSettings {
         self.children_type = ChildrenType::Sharable;
         self
     }
+
+    /// Do not load comments into the tree.
+    ///
+    /// False by default.
+    pub fn skip_comments(mut self, b: bool) -> Self {
+        self.set_skip_comments(b);
+        self
+    }
+
+    /// See [`skip_comments`].
+    pub fn set_skip_comments(&mut self, b: bool) {
+        self.skip_comments = b;
+    }
+
+    /// Decode character references like `&amp;` or `&#x2014;` in the text of the nodes.
+    /// When disabled, `Node::text` returns the text as it is written in the code. Decoded and
+    /// raw texts are available anyway with `Node::decoded_text` and `Node::raw_text`.
+    /// Attribute values are always decoded.
+    ///
+    /// True by default.
+    pub fn decode_entities(mut self, b: bool) -> Self {
+        self.set_decode_entities(b);
+        self
+    }
+
+    /// See [`decode_entities`].
+    pub fn set_decode_entities(&mut self, b: bool) {
+        self.decode_entities = b;
+    }
+
+    /// Fail on the first problem in the code like unclosed element or unmatched closing tag.
+    /// This is a default value.
+    pub fn strict(mut self) -> Self {
+        self.mode = ParseMode::Strict;
+        self
+    }
+
+    /// Recover from the problems in the code and collect them as diagnostics. Opposite to
+    /// `strict`.
+    pub fn recovering(mut self) -> Self {
+        self.mode = ParseMode::Recovering;
+        self
+    }
+
+    /// How to react on the problems in the code. See [`ParseMode`].
+    ///
+    /// `ParseMode::Strict` by default.
+    pub fn mode(mut self, mode: ParseMode) -> Self {
+        self.set_mode(mode);
+        self
+    }
+
+    /// See [`mode`].
+    pub fn set_mode(&mut self, mode: ParseMode) {
+        self.mode = mode;
+    }
+
+    /// How to handle whitespace in the texts between tags. See [`Whitespace`] for the nodes
+    /// each policy produces.
+    ///
+    /// `Whitespace::Trim` by default.
+    pub fn whitespace(mut self, policy: Whitespace) -> Self {
+        self.set_whitespace(policy);
+        self
+    }
+
+    /// See [`whitespace`].
+    pub fn set_whitespace(&mut self, policy: Whitespace) {
+        self.whitespace = policy;
+    }
+
+    /// Keep the case of tag and attribute names in the content of `<svg>` and `<math>`
+    /// elements the way XML does, so e.g. `<clipPath>` stays `clipPath`. Closing tags still
+    /// match the elements regardless of the case like in browsers. Otherwise all the names
+    /// are converted to lowercase.
+    ///
+    /// False by default.
+    pub fn keep_foreign_case(mut self, b: bool) -> Self {
+        self.set_keep_foreign_case(b);
+        self
+    }
+
+    /// See [`keep_foreign_case`].
+    pub fn set_keep_foreign_case(&mut self, b: bool) {
+        self.keep_foreign_case = b;
+    }
+
+    /// Encoding of the code loaded from bytes when it does not declare its own encoding.
+    ///
+    /// Windows-1252 by default.
+    pub fn default_encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.set_default_encoding(encoding);
+        self
+    }
+
+    /// See [`default_encoding`].
+    pub fn set_default_encoding(&mut self, encoding: &'static Encoding) {
+        self.default_encoding = encoding;
+    }
+
+    /// Keep the code of tags and markup as it was written, including quotes, whitespace and
+    /// the case of names, along with the code the parser drops, so that `Node::to_string`
+    /// writes back exactly the loaded code. Only the tags and attributes that were changed
+    /// are written anew.
+    ///
+    /// False by default.
+    pub fn lossless(mut self, b: bool) -> Self {
+        self.set_lossless(b);
+        self
+    }
+
+    /// See [`lossless`].
+    pub fn set_lossless(&mut self, b: bool) {
+        self.lossless = b;
+    }
+
+    /// Which attribute is kept when the tag has several attributes with the same name.
+    /// Every repeated attribute is reported as a problem in the code. Parsing fails on it
+    /// only with `DuplicateAttributes::Error`, in strict mode too.
+    ///
+    /// `DuplicateAttributes::FirstWins` by default.
+    pub fn duplicate_attributes(mut self, policy: DuplicateAttributes) -> Self {
+        self.set_duplicate_attributes(policy);
+        self
+    }
+
+    /// See [`duplicate_attributes`].
+    pub fn set_duplicate_attributes(&mut self, policy: DuplicateAttributes) {
+        self.duplicate_attributes = policy;
+    }
+
+    /// Whether parsing fails because of given problem in the code.
+    fn fails_on(&self, error: &Error) -> bool {
+        match error.kind {
+            ErrorKind::DuplicateAttribute(_, DuplicateAttributes::Error) => true,
+            // Other policies resolve the repeated attribute.
+            ErrorKind::DuplicateAttribute(..) => false,
+            _ => self.mode == ParseMode::Strict,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1382,6 +3104,24 @@ mod tests {
         assert_eq!("Some  ", first.children().get(0).unwrap().text().unwrap());
     }
 
+    #[test]
+    fn whitespace() {
+        let html = "<p>\n  <b>a</b> <i>b</i>\t c \n</p>\n";
+        let load = |policy| {
+            let settings = LoadSettings::new().whitespace(policy);
+            Node::from_html(html, &settings).unwrap().unwrap().to_string()
+        };
+
+        assert_eq!("<p><b>a</b><i>b</i>\t c</p>", load(Whitespace::Trim));
+        assert_eq!(html, load(Whitespace::Preserve));
+        assert_eq!("<p><b>a</b><i>b</i>\t c \n</p>", load(Whitespace::DropEmpty));
+        assert_eq!("<p> <b>a</b> <i>b</i> c </p> ", load(Whitespace::Collapse));
+
+        let settings = LoadSettings::new().whitespace(Whitespace::Collapse);
+        let root = Node::from_html("<script>\n  a  </script>", &settings).unwrap().unwrap();
+        assert_eq!("\n  a  ", root.children()[0].children()[0].text().unwrap());
+    }
+
     #[test]
     fn node_to_html() {
         let html = "<p><i>Text</i><br></p>";
@@ -1409,4 +3149,823 @@ mod tests {
 
         assert_eq!("<a href=\"b\">", &html);
     }
+
+    #[test]
+    fn from_html_error() {
+        let html = "<div>\n  <p>Text</p>\n  <!-- Comment";
+
+        let err = Node::from_html(html, &Default::default()).unwrap_err();
+        assert_eq!(&ErrorKind::UnexpectedEof(String::from("comment")), err.kind());
+        assert_eq!(3, err.position().line());
+
+        let err = Node::from_html_first(html, &Default::default()).unwrap_err();
+        assert_eq!(3, err.position().line());
+
+        // Comment lasts till the end of the code in recovering mode.
+        let settings = LoadSettings::new().recovering();
+        let result = Node::from_html_with_diagnostics(html, &settings);
+        let (root, diagnostics) = result.unwrap();
+        let div = root.unwrap().children().get(0).unwrap().clone();
+        assert_eq!(" Comment", div.children().last().unwrap().comment().unwrap());
+        assert_eq!(2, diagnostics.len());
+        assert_eq!(&ErrorKind::UnexpectedEof(String::from("comment")), diagnostics[0].kind());
+        assert_eq!(&ErrorKind::UnclosedElement(String::from("div")), diagnostics[1].kind());
+    }
+
+    #[test]
+    fn parse_modes() {
+        let html = "<div><span>a</p></div><ul><li>1</ul><!x-y><p>b";
+
+        let settings = LoadSettings::new().recovering();
+        let (root, diagnostics) = Node::from_html_with_diagnostics(html, &settings).unwrap();
+        assert_eq!("<div><span>a<p></p></div><ul><li>1</ul><!--x-y--><p>b",
+            &root.unwrap().to_string());
+        let kinds: Vec<_> = diagnostics.iter().map(|d| d.kind().clone()).collect();
+        assert_eq!(vec![
+            ErrorKind::UnmatchedEndTag(String::from("p")),
+            ErrorKind::UnclosedElement(String::from("span")),
+            ErrorKind::UnexpectedBang,
+        ], kinds);
+        assert_eq!(13, diagnostics[0].position().column());
+        assert_eq!(17, diagnostics[1].position().column());
+
+        let err = Node::from_html(html, &Default::default()).unwrap_err();
+        assert_eq!(diagnostics[0], err);
+        let settings = settings.mode(ParseMode::Strict);
+        assert_eq!(err, Node::from_html_first(html, &settings).unwrap_err());
+
+        // Omitted closing tags are not errors, even at the end of the code.
+        let html = "<ul><li>1<li>2</ul><table><tr><td>3</table><div><p>a";
+        assert!(Node::from_html(html, &settings).unwrap().is_some());
+
+        // Only the code of the first node is checked.
+        let html = "<p>a<p x x>b<!-- c";
+        let first = Node::from_html_first(html, &settings).unwrap().unwrap();
+        assert_eq!("<p>a", &first.to_string());
+    }
+
+    #[test]
+    fn from_html_continues_after_comment() {
+        let html = "<p>a</p><!-- Comment --><p>b</p>";
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        assert_eq!(3, result.children().len());
+        assert_eq!("p", result.children().get(2).unwrap().tag_name().unwrap());
+    }
+
+    #[test]
+    fn void_elements() {
+        let html = "<p>a<br>b<img src=\"a\"></br>c</p>";
+
+        let settings = LoadSettings::new().recovering();
+        let result = Node::from_html(html, &settings).unwrap().unwrap();
+        let p = result.children().get(0).unwrap();
+        let children = p.children();
+        assert_eq!(5, children.len());
+        assert_eq!("br", children.get(1).unwrap().tag_name().unwrap());
+        assert!(children.get(1).unwrap().children().is_empty());
+        assert_eq!("b", children.get(2).unwrap().text().unwrap());
+        assert_eq!("img", children.get(3).unwrap().tag_name().unwrap());
+        assert_eq!("c", children.get(4).unwrap().text().unwrap());
+
+        assert_eq!("<p>a<br>b<img src=\"a\">c</p>", &result.to_string());
+    }
+
+    #[test]
+    fn implied_end_tags() {
+        let html = "<ul><li>one<li>two</ul><p>a<p>b<div>c</div>\
+            <table><tr><td>1<td>2<tr><td>3</table>\
+            <select><option>x<option>y</select>";
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        assert_eq!("<ul><li>one<li>two</ul><p>a<p>b<div>c</div>\
+            <table><tr><td>1<td>2<tr><td>3</table>\
+            <select><option>x<option>y</select>", &result.to_string());
+
+        let ul = result.children().get(0).unwrap();
+        assert_eq!(2, ul.children().len());
+        assert_eq!("two", ul.children().get(1).unwrap().children().get(0).unwrap().text().unwrap());
+
+        let names: Vec<_> = result.children().iter().map(|n| n.tag_name().unwrap()).collect();
+        assert_eq!(vec!["ul", "p", "p", "div", "table", "select"], names);
+
+        let table = result.children().get(4).unwrap();
+        assert_eq!(2, table.children().len());
+        assert_eq!(2, table.children().get(0).unwrap().children().len());
+
+        let select = result.children().get(5).unwrap();
+        assert_eq!(2, select.children().len());
+    }
+
+    #[test]
+    fn unmatched_end_tag_ignored() {
+        let html = "<div><span>a</SPAN></b>b</div><p>c</p>";
+
+        let settings = LoadSettings::new().recovering();
+        let result = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!(2, result.children().len());
+        assert_eq!("<div><span>a</span>b</div><p>c</p>", &result.to_string());
+
+        // Stray closing tag of paragraph gives empty paragraph.
+        let html = "<div><span>a</p></span>b</div>";
+        let result = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!("<div><span>a<p></p></span>b</div>", &result.to_string());
+        let settings = settings.lossless(true);
+        let result = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!(html, &result.to_string());
+
+        // Closing tag of another heading closes the heading but is still a problem.
+        let html = "<h1>x</h2>";
+        let error = Node::from_html(html, &Default::default()).unwrap_err();
+        assert_eq!(&ErrorKind::UnmatchedEndTag(String::from("h2")), error.kind());
+        let recovering = LoadSettings::new().recovering();
+        let (result, diagnostics) = Node::from_html_with_diagnostics(html, &recovering).unwrap();
+        let result = result.unwrap();
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(5, diagnostics[0].position().offset());
+        assert_eq!(Some("h1"), result.children()[0].end());
+        assert_eq!("<h1>x</h1>", &result.to_string());
+        let result = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!(html, &result.to_string());
+    }
+
+    #[test]
+    fn self_closing_attributes() {
+        let html = r#"<img src="x.png" alt="logo"/><input type="text" name="q"/>"#;
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let img = result.children().get(0).unwrap();
+        assert!(img.start().as_ref().unwrap().is_self_closing());
+        assert_eq!("x.png", img.attribute_by_name("src").unwrap().first_value());
+        assert_eq!("logo", img.attribute_by_name("alt").unwrap().first_value());
+
+        let fetch = result.children_fetch().key("name").value("q").fetch();
+        assert_eq!(1, fetch.len());
+        assert_eq!("input", fetch.front().unwrap().tag_name().unwrap());
+
+        assert_eq!(html, &result.to_string());
+    }
+
+    #[test]
+    fn comments() {
+        let html = "<div><!-- BEGIN:nav --><nav>a</nav><!-- END:nav --></div>";
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let div = result.children().get(0).unwrap();
+        assert_eq!(3, div.children().len());
+        assert_eq!(" BEGIN:nav ", div.children().get(0).unwrap().comment().unwrap());
+        assert!(div.children().get(0).unwrap().text().is_none());
+        assert_eq!(html, &result.to_string());
+
+        let fetch = result.children_fetch().comment("END:nav").fetch();
+        assert_eq!(1, fetch.len());
+        assert_eq!(&div.children().get(2).unwrap(), fetch.front().unwrap());
+
+        let settings = LoadSettings::new().skip_comments(true);
+        let result = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!("<div><nav>a</nav></div>", &result.to_string());
+    }
+
+    #[test]
+    fn doctype_and_declaration() {
+        let html = "<?xml version=\"1.0\"?><!DOCTYPE html><?php echo 1; ?><html></html>";
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        assert_eq!(4, result.children().len());
+        assert_eq!("html", result.doctype().unwrap());
+        assert_eq!("xml version=\"1.0\"", result.declaration().unwrap());
+        assert_eq!("php echo 1; ",
+            result.children().get(2).unwrap().processing_instruction().unwrap());
+        assert!(result.children().get(3).unwrap().doctype().is_none());
+
+        assert_eq!(html, &result.to_string());
+    }
+
+    #[test]
+    fn cdata() {
+        let html = "<script><![CDATA[if (a < b && c) { x(\"</p>\"); }]]></script>";
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let script = result.children().get(0).unwrap();
+        assert_eq!(1, script.children().len());
+        let cdata = script.children().get(0).unwrap();
+        assert_eq!("if (a < b && c) { x(\"</p>\"); }", cdata.cdata().unwrap());
+        assert!(cdata.text().is_none());
+
+        assert_eq!(html, &result.to_string());
+    }
+
+    #[test]
+    fn raw_text_elements() {
+        let html = "<script>\n  if (a < b) { s = \"</div>\"; }\n</script>\
+            <style>p > a { color: red }</style><title>A &amp; <b></TITLE><p>a < b</p>";
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        assert_eq!(4, result.children().len());
+
+        let script = result.children().get(0).unwrap();
+        assert_eq!(1, script.children().len());
+        assert_eq!("\n  if (a < b) { s = \"</div>\"; }\n",
+            script.children().get(0).unwrap().text().unwrap());
+
+        let style = result.children().get(1).unwrap();
+        assert_eq!("p > a { color: red }", style.children().get(0).unwrap().text().unwrap());
+
+        let title = result.children().get(2).unwrap();
+        assert_eq!("A & <b>", title.children().get(0).unwrap().text().unwrap());
+
+        let p = result.children().get(3).unwrap();
+        assert_eq!("a < b", p.children().get(0).unwrap().text().unwrap());
+
+        assert_eq!(html.replace("TITLE", "title"), result.to_string());
+
+        // Raw text elements exist only in HTML.
+        let html = "<svg><style><b>a</b></style></svg><xmp><i>b</i></xmp>";
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let style = result.children()[0].children()[0].clone();
+        assert_eq!("b", style.children()[0].tag_name().unwrap());
+        let xmp = result.children()[1].clone();
+        assert_eq!("i", xmp.children()[0].tag_name().unwrap());
+        assert_eq!(html, &result.to_string());
+    }
+
+    #[test]
+    fn character_references() {
+        let html = "<p title=\"a &quot;b&quot; &notit; &copy=1\">\
+            Tom &amp; Jerry&nbsp;&#x2014;&#151;&notit;</p>";
+
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let p = result.children().get(0).unwrap();
+        let text = p.children().get(0).unwrap();
+        assert_eq!("Tom & Jerry\u{A0}\u{2014}\u{2014}\u{AC}it;", text.text().unwrap());
+        assert_eq!("Tom &amp; Jerry&nbsp;&#x2014;&#151;&notit;", text.raw_text().unwrap());
+        // Legacy references are not decoded in attributes if followed by alphanumeric or '='.
+        let title = p.attribute_by_name("title").unwrap();
+        assert_eq!("a \"b\" &notit; &copy=1", title.values_to_string());
+        assert_eq!("<p title=\"a &quot;b&quot; &amp;notit; &amp;copy=1\">\
+            Tom &amp; Jerry&nbsp;&#x2014;&#151;&notit;</p>", &result.to_string());
+
+        let settings = LoadSettings::new().decode_entities(false);
+        let result = Node::from_html(html, &settings).unwrap().unwrap();
+        let text = result.children().get(0).unwrap().children().get(0).unwrap();
+        assert_eq!("Tom &amp; Jerry&nbsp;&#x2014;&#151;&notit;", text.text().unwrap());
+        assert_eq!("Tom & Jerry\u{A0}\u{2014}\u{2014}\u{AC}it;", text.decoded_text().unwrap());
+
+        // Text is decoded even if its code is kept in lossless mode.
+        let settings = settings.lossless(true);
+        let result = Node::from_html("<p>\n  a &amp; b\n</p>", &settings).unwrap().unwrap();
+        let text = result.children()[0].children()[0].clone();
+        assert_eq!("a &amp; b", text.text().unwrap());
+        assert_eq!("a & b", text.decoded_text().unwrap());
+    }
+
+    #[test]
+    fn escape_to_html() {
+        let html = "<p><b>a</b></p><script></script>";
+        let mut result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+
+        let p = result.children_mut().get_mut(0).unwrap().try_mut().unwrap();
+        let attr = Attribute::from_name_and_str_values(
+            String::from("title"), "\"><script>alert('&')</script>");
+        p.put_attribute(attr).unwrap();
+        p.children_mut().push(Node::new_text("1 < 2 && 3 > 2\u{A0}").into());
+
+        let script = result.children_mut().get_mut(1).unwrap().try_mut().unwrap();
+        script.children_mut().push(Node::new_text("if (a < b && c) {}").into());
+
+        assert_eq!("<p title=\"&quot;&gt;&lt;script&gt;alert('&amp;')&lt;/script&gt;\">\
+            <b>a</b>1 &lt; 2 &amp;&amp; 3 &gt; 2&nbsp;</p><script>if (a < b && c) {}</script>",
+            &result.to_string());
+
+        let reloaded = Node::from_html(&result.to_string(), &Default::default());
+        let reloaded = reloaded.unwrap().unwrap();
+        let p = reloaded.children().get(0).unwrap();
+        assert_eq!("\"><script>alert('&')</script>",
+            p.attribute_by_name("title").unwrap().values_to_string());
+        assert_eq!("1 < 2 && 3 > 2\u{A0}", p.children().get(1).unwrap().text().unwrap());
+
+        // Content cannot end the element or markup it is in.
+        let mut root = Node::new();
+        let mut script = Node::from_html("<script></script>", &Default::default())
+            .unwrap().unwrap().children()[0].to_owned();
+        let text = "s = \"</SCRIPT><img src=x onerror=alert(1)>\";";
+        script.children_mut().push(Node::new_text(text).into());
+        script.put_attribute(Attribute::from_name(String::from("onload=alert(1) x"))).unwrap();
+        root.children_mut().push(script.into());
+        let markup = [
+            Markup::Comment(String::from("-> a --> <b>")),
+            Markup::CData(String::from("a]]><b>")),
+            Markup::Doctype(String::from(" html><b>")),
+        ];
+        for markup in markup.iter() {
+            root.children_mut().push(Node { markup: Some(markup.clone()), ..Node::new() }.into());
+        }
+        assert_eq!("<script>s = \"<\\/SCRIPT><img src=x onerror=alert(1)>\";</script>\
+            <!-- -> a -- > <b>--><![CDATA[a]]]]><![CDATA[><b>]]><!DOCTYPE html<b>",
+            &root.to_string());
+
+        let reloaded = Node::from_html(&root.to_string(), &Default::default());
+        let reloaded = reloaded.unwrap().unwrap();
+        assert_eq!(5, reloaded.children().len());
+        assert!(reloaded.children()[0].attributes().unwrap().is_empty());
+        assert_eq!(1, reloaded.children()[0].children().len());
+    }
+
+    #[test]
+    fn spans() {
+        let html = "<div id=\"a\">\n  <p>Привет</p>\n  <ul><li>1</ul>\n</div>";
+        let result = Node::from_html(html, &Default::default()).unwrap().unwrap();
+
+        let div = result.children().get(0).unwrap();
+        let span = div.span().unwrap();
+        assert_eq!(0..html.len(), span.range());
+        assert_eq!((1, 1), (span.start().line(), span.start().column()));
+        assert_eq!((4, 7), (span.end().line(), span.end().column()));
+        assert_eq!(0..12, div.start().as_ref().unwrap().span().unwrap().range());
+        let end = div.end_span().unwrap();
+        assert_eq!((4, 1), (end.start().line(), end.start().column()));
+
+        let p = div.children().get(0).unwrap();
+        let span = p.span().unwrap();
+        assert_eq!("<p>Привет</p>", &html[span.range()]);
+        assert_eq!((2, 3), (span.start().line(), span.start().column()));
+        assert_eq!((2, 16), (span.end().line(), span.end().column()));
+
+        // Closing tag of the list item is omitted so it ends with its text.
+        let ul = div.children().get(1).unwrap();
+        let li = ul.children().get(0).unwrap();
+        assert_eq!("<li>1", &html[li.span().unwrap().range()]);
+        assert!(li.end_span().is_none());
+        assert_eq!("<ul><li>1</ul>", &html[ul.span().unwrap().range()]);
+
+        assert!(Node::new_text("a").span().is_none());
+    }
+
+    #[test]
+    fn equal_nodes() {
+        // Positions and the code kept in lossless mode are not compared.
+        let html = "<div>\n  <p class=\"a\">x</p></div>";
+        let root = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let div = root.children()[0].clone();
+        let other = Node::from_html("<p class=a>x</p >", &Default::default()).unwrap().unwrap();
+        assert_eq!(*div.children()[0], *other.children()[0]);
+        assert_ne!(*div.children()[0], *div.children()[0].children()[0]);
+
+        let settings = LoadSettings::new().lossless(true);
+        let lossless = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!(root, lossless);
+    }
+
+    #[test]
+    fn from_html_bytes() {
+        let text = |root: Option<Node>| {
+            let root = root.unwrap();
+            let p = root.children().last().unwrap().clone();
+            String::from(p.children().get(0).unwrap().text().unwrap())
+        };
+
+        let html = b"<meta charset=\"iso-8859-1\"><p>caf\xE9</p>";
+        let (root, encoding) = Node::from_html_bytes(html, &Default::default()).unwrap();
+        assert_eq!(encoding_rs::WINDOWS_1252, encoding);
+        assert_eq!("caf\u{E9}", text(root));
+
+        let html = b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset='utf-8'\">\
+            <p>caf\xC3\xA9</p>";
+        let (root, encoding) = Node::from_html_bytes(html, &Default::default()).unwrap();
+        assert_eq!(encoding_rs::UTF_8, encoding);
+        assert_eq!("caf\u{E9}", text(root));
+
+        let mut html = vec![0xFF, 0xFE];
+        for c in "<p>caf\u{E9}</p>".encode_utf16() {
+            html.extend_from_slice(&c.to_le_bytes());
+        }
+        let (root, encoding) = Node::from_html_bytes(&html, &Default::default()).unwrap();
+        assert_eq!(encoding_rs::UTF_16LE, encoding);
+        assert_eq!("caf\u{E9}", text(root));
+
+        let html = b"<p>caf\xC3\xA9</p>";
+        let (_, encoding) = Node::from_html_bytes(html, &Default::default()).unwrap();
+        assert_eq!(encoding_rs::WINDOWS_1252, encoding);
+        let settings = LoadSettings::new().default_encoding(encoding_rs::UTF_8);
+        let (root, encoding) = Node::from_html_bytes(html, &settings).unwrap();
+        assert_eq!(encoding_rs::UTF_8, encoding);
+        assert_eq!("caf\u{E9}", text(root));
+    }
+
+    #[test]
+    fn from_reader() {
+        let html = "<!DOCTYPE html>\n<html><head><title>Caf\u{E9} &amp; co</title>\
+            <script>if (a < b) {}</script></head>\n<body><!-- Привет --><p class=\"x\">a&nbsp;\
+            <b>жирный</b> &#x2014; <![CDATA[c]]><br/></p><ul><li>1<li>2</ul></body></html>\n";
+        let expected = Node::from_html(html, &Default::default()).unwrap().unwrap();
+
+        for capacity in 1..10 {
+            let reader = std::io::BufReader::with_capacity(capacity, html.as_bytes());
+            let result = Node::from_reader(reader, &Default::default()).unwrap().unwrap();
+            assert_eq!(expected, result);
+        }
+
+        let reader = std::io::BufReader::with_capacity(2, "<p>a</p><!-- b".as_bytes());
+        let err = Node::from_reader(reader, &LoadSettings::new().strict()).unwrap_err();
+        assert_eq!(&ErrorKind::UnexpectedEof(String::from("comment")), err.kind());
+        assert_eq!(9, err.position().column());
+
+        let reader = std::io::BufReader::with_capacity(3, "<p>a</p><!-- b".as_bytes());
+        let settings = LoadSettings::new().recovering();
+        let (root, diagnostics) = Node::from_reader_with_diagnostics(reader, &settings).unwrap();
+        assert_eq!(" b", root.unwrap().children()[1].comment().unwrap());
+        assert_eq!(vec![err], diagnostics);
+    }
+
+    #[test]
+    fn parser_chunks() {
+        let html = "<p title=\"a &amp; b\">Привет &mdash; мир</p><!-- x --><br>".as_bytes();
+        let (expected, _) = Node::from_html_with_diagnostics(
+            std::str::from_utf8(html).unwrap(), &Default::default()).unwrap();
+
+        // Split the code at every byte, including those inside of characters.
+        for i in 0..html.len() {
+            let settings = Default::default();
+            let mut parser = Parser::new(&settings);
+            parser.feed(&html[..i]).unwrap();
+            parser.feed(&html[i..]).unwrap();
+            assert_eq!(expected, parser.finish().unwrap(), "split at {}", i);
+        }
+
+        let settings = LoadSettings::new().recovering();
+        let mut parser = Parser::new(&settings);
+        parser.feed(b"<div>a</p></div>").unwrap();
+        let (_, diagnostics) = parser.finish_with_diagnostics().unwrap();
+        assert_eq!(&ErrorKind::UnmatchedEndTag(String::from("p")), diagnostics[0].kind());
+        assert!(Parser::new(&settings).finish().unwrap().is_none());
+
+        // Parser stops on the first problem in strict mode.
+        let settings = LoadSettings::new();
+        let mut parser = Parser::new(&settings);
+        parser.feed(b"<div>a</p").unwrap();
+        let err = parser.feed(b"><p>b</p></div>").unwrap_err();
+        assert_eq!(&ErrorKind::UnmatchedEndTag(String::from("p")), err.kind());
+        assert_eq!(err, parser.feed(b"<p>c</p>").unwrap_err());
+        assert_eq!(err, parser.finish().unwrap_err());
+    }
+
+    #[test]
+    fn events() {
+        let html = "<p class=\"a b\">\n  x &amp; y\n</i><!-- c --><script>a &amp; b</script><br>";
+        let settings = Default::default();
+        let events: Vec<_> = Events::new(html, &settings).map(|e| e.unwrap().0).collect();
+        assert_eq!(8, events.len());
+        match events[0] {
+            Event::StartTag(ref tag) => {
+                assert_eq!("p", tag.name());
+                assert_eq!("b", tag.attribute_by_name("class").unwrap().tokens().nth(1).unwrap());
+            },
+            _ => panic!(),
+        }
+        assert_eq!(Event::Text {
+            text: String::from("x & y"),
+            raw_text: String::from("x &amp; y"),
+        }, events[1]);
+        assert_eq!(Event::EndTag(String::from("i")), events[2]);
+        assert_eq!(Event::Markup(Markup::Comment(String::from(" c "))), events[3]);
+        assert_eq!(Event::Text {
+            text: String::from("a &amp; b"),
+            raw_text: String::from("a &amp; b"),
+        }, events[5]);
+
+        let settings = LoadSettings::new().skip_comments(true).strict();
+        let mut events = Events::new("<p>a</p><!-- b --><!x><br>", &settings);
+        assert_eq!(3, events.by_ref().take(3).filter(|e| e.is_ok()).count());
+        let err = events.next().unwrap().unwrap_err();
+        assert_eq!(&ErrorKind::UnexpectedBang, err.kind());
+        assert!(events.next().is_none());
+
+        let settings = LoadSettings::new().recovering();
+        let mut events = Events::new("<p a a><!x><br>", &settings);
+        assert_eq!(2, events.by_ref().take(2).filter(|e| e.is_ok()).count());
+        let diagnostics = events.take_diagnostics();
+        assert_eq!(2, diagnostics.len());
+        assert_eq!(&ErrorKind::UnexpectedBang, diagnostics[1].kind());
+        assert_eq!(1, events.count());
+    }
+
+    #[test]
+    fn from_html_fragment() {
+        let settings = LoadSettings::new().recovering();
+
+        let html = "<td>x</td><td>y</tr><td>z";
+        let root = Node::from_html_fragment(html, "tr", &settings).unwrap().unwrap();
+        assert_eq!(3, root.children().len());
+        assert!(root.children().iter().all(|c| c.tag_name() == Some("td")));
+        assert_eq!("<td>x</td><td>y<td>z", &root.to_string());
+
+        let html = "<option>a<option>b</select><optgroup><option>c";
+        let root = Node::from_html_fragment(html, "SELECT", &settings).unwrap().unwrap();
+        assert_eq!(3, root.children().len());
+        assert_eq!("optgroup", root.children()[2].tag_name().unwrap());
+
+        // Paragraph of the context is not closed by the nested one.
+        let root = Node::from_html_fragment("a<p>b</p>c", "p", &settings).unwrap().unwrap();
+        assert_eq!(3, root.children().len());
+
+        let html = "<b>a &amp; b</b>";
+        let root = Node::from_html_fragment(html, "textarea", &settings).unwrap().unwrap();
+        assert_eq!(1, root.children().len());
+        assert_eq!("<b>a & b</b>", root.children()[0].text().unwrap());
+
+        assert!(Node::from_html_fragment("", "div", &settings).unwrap().is_none());
+    }
+
+    #[test]
+    fn preformatted() {
+        let html = "<div>\n  <pre>\n\n  fn main() {}\n</pre>\n  <textarea>\r\n a\n</textarea>\
+            <listing>x\n</listing><pre>\n</pre></div>";
+        let settings = LoadSettings::new().whitespace(Whitespace::Collapse);
+        let root = Node::from_html(html, &settings).unwrap().unwrap();
+        let div = root.children()[0].clone();
+
+        let find = |name| div.children().iter().find(|c| c.tag_name() == Some(name)).unwrap();
+
+        let pre = find("pre");
+        assert_eq!("\n  fn main() {}\n", pre.children()[0].text().unwrap());
+        let span = pre.children()[0].span().unwrap();
+        assert_eq!(&html[span.range()], "\n  fn main() {}\n");
+        assert_eq!(" a\n", find("textarea").children()[0].text().unwrap());
+        assert_eq!("x\n", find("listing").children()[0].text().unwrap());
+        assert!(div.children().last().unwrap().children().is_empty());
+        assert_eq!("<pre>\n\n  fn main() {}\n</pre>", &pre.to_string());
+
+        let root = Node::from_html_fragment("\nx", "pre", &settings).unwrap().unwrap();
+        assert_eq!("\nx", root.children()[0].text().unwrap());
+
+        // Element closed by the end of its parent does not keep the text after it.
+        let html = "<div><pre>x</div><p>\n  a  b\n</p><pre>\ny</pre>";
+        let settings = settings.recovering();
+        let root = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!("<div><pre>x</div><p> a b </p><pre>y</pre>", &root.to_string());
+
+        let mut parser = Parser::new(&settings);
+        parser.feed(b"<pre>").unwrap();
+        parser.feed(b"\ny</pre>").unwrap();
+        let root = parser.finish().unwrap().unwrap();
+        assert_eq!("y", root.children()[0].children()[0].text().unwrap());
+    }
+
+    #[test]
+    fn case_insensitive_names() {
+        let html = "<DIV ID=\"a\"><Span>x</SPAN></div><svg viewBox=\"0 0 1 1\"><clipPath>\
+            <Rect/></clippath></SVG>";
+        let root = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        assert_eq!(2, root.children().len());
+        let div = root.children()[0].clone();
+        assert_eq!("div", div.tag_name().unwrap());
+        assert_eq!("id", div.attributes().unwrap()[0].name());
+        assert_eq!("a", div.attribute_by_name("Id").unwrap().first_value());
+        assert_eq!(1, root.children_fetch().tag("Div").key("ID").fetch().len());
+        assert_eq!("<div id=\"a\"><span>x</span></div>", &div.to_string());
+
+        // Names in foreign content keep their case but closing tags still match regardless
+        // of it.
+        let settings = LoadSettings::new().keep_foreign_case(true);
+        let root = Node::from_html(html, &settings).unwrap().unwrap();
+        let svg = root.children()[1].clone();
+        assert_eq!("svg", svg.tag_name().unwrap());
+        assert_eq!("viewBox", svg.attributes().unwrap()[0].name());
+        let clip_path = svg.children()[0].clone();
+        assert_eq!("clipPath", clip_path.tag_name().unwrap());
+        assert_eq!(Some("clippath"), clip_path.end());
+        assert_eq!("Rect", clip_path.children()[0].tag_name().unwrap());
+        assert_eq!(Some("svg"), svg.end());
+
+        // Foreign element closed by the end of its parent does not keep the case of the names
+        // after it.
+        let html = "<div><svg><clipPath></div><Span ID=a><svg><foreignObject><B>x</B>";
+        let root = Node::from_html(html, &settings.recovering()).unwrap().unwrap();
+        let span = root.children()[1].clone();
+        assert_eq!("span", span.tag_name().unwrap());
+        assert_eq!("id", span.attributes().unwrap()[0].name());
+        let foreign_object = span.children()[0].children()[0].clone();
+        assert_eq!("foreignObject", foreign_object.tag_name().unwrap());
+        assert_eq!("b", foreign_object.children()[0].tag_name().unwrap());
+    }
+
+    #[test]
+    fn namespaces() {
+        let html = "<div><svg xmlns:ex=\"urn:ex\"><a xlink:href=\"#x\" ex:y=\"1\" xml:lang=\"en\">\
+            <foreignObject><p title=\"t\" xlink:href=\"b\">t</p></foreignObject></a></svg>\
+            <math><mi id=\"i\"><b id=\"b\">x</b></mi></math><x xmlns=\"urn:x\" id=\"x\"></x></div>";
+        let root = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let div = root.children()[0].clone();
+        assert_eq!(&Namespace::Html, div.start().as_ref().unwrap().namespace());
+
+        let svg = div.children()[0].clone();
+        assert_eq!(&Namespace::Svg, svg.start().as_ref().unwrap().namespace());
+        let a = svg.children()[0].clone();
+        assert_eq!(&Namespace::Svg, a.start().as_ref().unwrap().namespace());
+        let href = a.attribute_by_name("xlink:href").unwrap();
+        assert_eq!(Some("xlink"), href.prefix());
+        assert_eq!("href", href.local_name());
+        assert_eq!(Some(&Namespace::XLink), href.namespace());
+        let ex = a.attribute_by_name("ex:y").unwrap();
+        assert_eq!(Some(&Namespace::Other(String::from("urn:ex"))), ex.namespace());
+        assert_eq!(Some(&Namespace::Xml), a.attribute_by_name("xml:lang").unwrap().namespace());
+
+        // Content of foreignObject is HTML again.
+        let p = a.children()[0].children()[0].clone();
+        assert_eq!(&Namespace::Html, p.start().as_ref().unwrap().namespace());
+        assert_eq!(None, p.attribute_by_name("xlink:href").unwrap().namespace());
+
+        let math = div.children()[1].clone();
+        assert_eq!(&Namespace::MathMl, math.start().as_ref().unwrap().namespace());
+        let x = div.children()[2].clone();
+        assert_eq!("urn:x", x.start().as_ref().unwrap().namespace().uri());
+
+        let mathml = root.children_fetch().key("id").namespace(&Namespace::MathMl).fetch();
+        assert_eq!(1, mathml.len());
+        assert_eq!("mi", mathml.front().unwrap().tag_name().unwrap());
+        let html_ids = root.children_fetch().key("id").namespace(&Namespace::Html).fetch();
+        assert_eq!(1, html_ids.len());
+
+        // Elements are found once each, also the ones without attributes.
+        let mathml = root.children_fetch().namespace(&Namespace::MathMl).fetch();
+        let names: Vec<_> = mathml.iter().map(|node| node.tag_name().unwrap()).collect();
+        assert_eq!(vec!["math", "mi"], names);
+        let svg = root.children_fetch().namespace(&Namespace::Svg).fetch();
+        let names: Vec<_> = svg.iter().map(|node| node.tag_name().unwrap()).collect();
+        assert_eq!(vec!["svg", "a", "foreignobject"], names);
+
+        // Elements are found inside the ones that do not match.
+        let html = "<section><Div id=d><svg><g><rect id=r></rect></g></svg></Div></section>";
+        let root = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let svg = Namespace::Svg;
+        assert_eq!(1, root.children_fetch().namespace(&svg).tag("rect").fetch().len());
+        assert_eq!(1, root.children_fetch().tag("rect").key("id").fetch().len());
+        assert_eq!(1, root.children_fetch().tag("div").key("id").fetch().len());
+        assert_eq!(0, root.children_fetch().namespace(&Namespace::Html).tag("rect").fetch().len());
+    }
+
+    #[test]
+    fn raw_attribute_values() {
+        let html = "<img alt=\"A  picture\" style=\"color: red\" class=\" a\tb \" onclick=\"f(a, b)\">";
+        let root = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let img = &root.children()[0];
+        assert_eq!("A  picture", img.attribute_by_name("alt").unwrap().value());
+        assert_eq!("f(a, b)", img.attribute_by_name("onclick").unwrap().value());
+
+        let class = img.attribute_by_name("class").unwrap();
+        assert_eq!(" a\tb ", class.value());
+        assert_eq!(vec!["a", "b"], class.tokens().collect::<Vec<_>>());
+        assert_eq!("a", class.first_value());
+        assert_eq!(html, root.to_string());
+
+        let found = root.children_fetch().key("class").value_part("b").fetch();
+        assert_eq!(1, found.len());
+        let found = root.children_fetch().key("class").value("a b").fetch();
+        assert_eq!(1, found.len());
+        let found = root.children_fetch().key("alt").value("A  picture").fetch();
+        assert_eq!(1, found.len());
+
+        #[allow(deprecated)]
+        let values = class.values();
+        assert_eq!(&vec![String::from("a"), String::from("b")], values);
+        let mut alt = img.attribute_by_name("alt").unwrap().clone();
+        #[allow(deprecated)]
+        let result = alt.set_values_from_str(" x  y");
+        assert_eq!(Ok(()), result);
+        assert_eq!(" x  y", alt.value());
+        assert_eq!(&String::from("x"), alt.first_value());
+    }
+
+    #[test]
+    fn valueless_attributes() {
+        let html = "<form><input disabled name=\"a\"><input readonly=\"\"><option selected>x</option></form>";
+        let root = Node::from_html(html, &Default::default()).unwrap().unwrap();
+        let form = &root.children()[0];
+        let disabled = form.children()[0].attribute_by_name("disabled").unwrap();
+        assert!(!disabled.has_value());
+        assert_eq!("", disabled.value());
+        assert_eq!(0, disabled.tokens().count());
+        let readonly = form.children()[1].attribute_by_name("readonly").unwrap();
+        assert!(readonly.has_value());
+        assert_eq!(html, root.to_string());
+
+        assert_eq!(1, root.children_fetch().key("disabled").fetch().len());
+        assert_eq!(1, root.children_fetch().key("selected").fetch().len());
+
+        let mut input = form.children()[0].to_owned();
+        let mut attr = Attribute::from_name(String::from("checked"));
+        input.overwrite_attribute(attr.clone());
+        assert_eq!("<input disabled name=\"a\" checked>", input.to_string());
+        attr.set_value(String::from("checked"));
+        input.overwrite_attribute(attr);
+        assert_eq!("<input disabled name=\"a\" checked=\"checked\">", input.to_string());
+    }
+
+    #[test]
+    fn lossless() {
+        let html = "<!doctype html>\n<HTML lang=en>\n<Body class='a  b'   data-x = \"1\" hidden>\n\
+            <p>One<p>Two &amp; more</P>\n<pre>\nx</pre><textarea>\n</textarea>\n\
+            <!x><br/></br></span><img src=a.png alt=\"A &amp; B\" / >\n<!-- c -->\
+            <ul><li>a</li >\n</ul></body></html>\n<div";
+        let settings = LoadSettings::new().recovering().lossless(true);
+        let root = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!(html, root.to_string());
+
+        // The code is kept without changing the tree.
+        let lossy = settings.clone().lossless(false);
+        assert_eq!(Node::from_html(html, &lossy).unwrap().unwrap(), root);
+        let comments = root.children_fetch().comment("c").fetch();
+        assert_eq!(None, comments.front().unwrap().raw_text());
+        let body = root.children_fetch().key("hidden").fetch();
+        let names: Vec<_> = body.front().unwrap().children().iter()
+            .map(|child| child.tag_name().or(child.text()).or(child.comment()).unwrap())
+            .collect();
+        assert_eq!(vec!["p", "p", "pre", "textarea", "x", "br", "img", " c ", "ul"], names);
+
+        // Other settings change the tree but the code is kept anyway.
+        let other = settings.clone().skip_comments(true).whitespace(Whitespace::Collapse);
+        let root = Node::from_html(html, &other).unwrap().unwrap();
+        assert_eq!(html, root.to_string());
+        assert_eq!(Node::from_html(html, &other.clone().lossless(false)).unwrap().unwrap(), root);
+        assert!(root.children_fetch().comment("c").fetch().is_empty());
+
+        // Code that gives no nodes is kept too.
+        let skipping = settings.clone().skip_comments(true);
+        for html in ["  \n", "\n\n", "<!-- x -->"].iter() {
+            let root = Node::from_html(html, &skipping).unwrap().unwrap();
+            assert_eq!(*html, root.to_string());
+            assert!(root.children().is_empty());
+        }
+        assert_eq!(None, Node::from_html("", &skipping).unwrap());
+
+        let mut parser = Parser::new(&settings);
+        for chunk in html.as_bytes().chunks(3) {
+            parser.feed(chunk).unwrap();
+        }
+        let mut root = parser.finish().unwrap().unwrap();
+        assert_eq!(html, root.to_string());
+        assert_eq!(Node::from_html(html, &lossy).unwrap().unwrap(), root);
+
+        // Only the changed attribute is written anew.
+        let mut body = root.children_fetch_mut().key("data-x").fetch_mut();
+        let body = body.front_mut().unwrap().try_mut().unwrap();
+        body.overwrite_attribute(Attribute::from_name_and_str_values(
+            String::from("data-x"), "2"));
+        assert_eq!(html.replace("   data-x = \"1\"", " data-x=\"2\""), root.to_string());
+    }
+
+    #[test]
+    fn duplicate_attributes() {
+        let html = "<div class=\"a\" id=\"x\" CLASS=\"b\" class=c></div>";
+        let recovering = LoadSettings::new().recovering();
+        let (root, diagnostics) = Node::from_html_with_diagnostics(html, &recovering).unwrap();
+        let root = root.unwrap();
+        let div = &root.children()[0];
+        assert_eq!(2, div.attributes().unwrap().len());
+        assert_eq!("a", div.attribute_by_name("class").unwrap().value());
+        assert_eq!(2, diagnostics.len());
+        let kind = ErrorKind::DuplicateAttribute(String::from("class"),
+            DuplicateAttributes::FirstWins);
+        assert_eq!(&kind, diagnostics[0].kind());
+        assert_eq!(22, diagnostics[0].position().offset());
+        assert_eq!(32, diagnostics[1].position().offset());
+
+        // The policy is applied in strict mode too.
+        let (root, diagnostics) = Node::from_html_with_diagnostics(html, &Default::default())
+            .unwrap();
+        assert_eq!("a", root.unwrap().children()[0].attribute_by_name("class").unwrap().value());
+        assert_eq!(2, diagnostics.len());
+        let html = r#"<a class="a" class="b">"#;
+        assert!(Node::from_html(html, &Default::default()).unwrap().is_some());
+        let html = "<div class=\"a\" id=\"x\" CLASS=\"b\" class=c></div>";
+
+        let settings = recovering.clone().duplicate_attributes(DuplicateAttributes::LastWins);
+        let root = Node::from_html(html, &settings).unwrap().unwrap();
+        let attrs = root.children()[0].attributes().unwrap();
+        assert_eq!(vec!["id", "class"], attrs.iter().map(|a| a.name()).collect::<Vec<_>>());
+        assert_eq!("c", attrs[1].value());
+
+        let settings = recovering.clone().duplicate_attributes(DuplicateAttributes::KeepAll);
+        let root = Node::from_html(html, &settings).unwrap().unwrap();
+        assert_eq!(4, root.children()[0].attributes().unwrap().len());
+
+        let settings = recovering.clone().duplicate_attributes(DuplicateAttributes::Error);
+        let error = Node::from_html(html, &settings).unwrap_err();
+        assert_eq!(&ErrorKind::DuplicateAttribute(String::from("class"),
+            DuplicateAttributes::Error), error.kind());
+        assert!(Events::new(html, &settings).any(|event| event.is_err()));
+
+        // Dropped attributes are still written in lossless mode.
+        let settings = recovering.lossless(true).duplicate_attributes(DuplicateAttributes::LastWins);
+        assert_eq!(html, Node::from_html(html, &settings).unwrap().unwrap().to_string());
+        let html = "<div a=1 b=1 b=2 a=2></div>";
+        assert_eq!(html, Node::from_html(html, &settings).unwrap().unwrap().to_string());
+
+        // Changing the attribute next to the dropped one keeps the code of the dropped one.
+        let settings = settings.duplicate_attributes(DuplicateAttributes::FirstWins);
+        let root = Node::from_html("<div a=1 b=3 a=2>", &settings).unwrap().unwrap();
+        let mut div = root.children()[0].to_owned();
+        div.overwrite_attribute(Attribute::from_name_and_str_values(String::from("b"), "4"));
+        assert_eq!("<div a=1 b=\"4\" a=2>", div.to_string());
+    }
 }
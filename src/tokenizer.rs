@@ -0,0 +1,508 @@
+//! Splits HTML code into tokens: tags, texts, comments and other markup.
+//!
+//! The code was read by quick-xml before. XML reader cannot leave the content of raw text
+//! elements like `<script>` unparsed, and it stops on the code that is fine in HTML but not
+//! in XML, e.g. `a < b` in a text or attributes without quotes. So HTML is tokenized here.
+
+use crate::{Error, ErrorKind, Position, Span, is_one_of};
+use encoding_rs::{CoderResult, Decoder, UTF_8};
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// Elements which content is not parsed as HTML but is read as is until the closing tag.
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+
+/// Raw text elements that can contain character references in their content.
+const ESCAPABLE_RAW_TEXT_ELEMENTS: &[&str] = &["textarea", "title"];
+
+/// Smallest part of HTML code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    /// Opening tag with its name and attributes.
+    StartTag {
+        name: String,
+        attrs: Vec<TokenAttribute>,
+        self_closing: bool,
+    },
+
+    /// Closing tag with its name.
+    EndTag(String),
+
+    /// Text between tags.
+    Text(String),
+
+    /// Content of raw text element like `<script>` or `<style>`. It is stored exactly as it
+    /// appears in the code.
+    RawText {
+        text: String,
+
+        /// Whether character references can be used in the text.
+        escapable: bool,
+    },
+
+    /// Text between `<!--` and `-->`.
+    Comment(String),
+
+    /// Text between `<!DOCTYPE` and `>`.
+    Doctype(String),
+
+    /// Text between `<?` and `?>` of XML declaration.
+    Declaration(String),
+
+    /// Text between `<?` and `?>` of processing instruction.
+    Instruction(String),
+
+    /// Text between `<![CDATA[` and `]]>`. It can be in the content of raw text elements
+    /// too.
+    CData(String),
+}
+
+/// Attribute as it is written in the opening tag.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenAttribute {
+    pub name: String,
+
+    /// Value without quotes. None if attribute has no value at all.
+    pub value: Option<String>,
+
+    /// Part of the code of the tag with this attribute. It includes the code between this
+    /// attribute and the previous one or the tag name.
+    pub source: Range<usize>,
+}
+
+/// Reads tokens from HTML code one by one. The code can be given at once or be fed in parts.
+pub struct Tokenizer<'a> {
+    /// Code that is not read yet. It can be preceded by some already read code.
+    html: Cow<'a, str>,
+
+    /// Offset of the next token in `html`.
+    pos: usize,
+
+    /// Offset of the last read token in `html`.
+    token_start: usize,
+
+    /// Offset in `html` where the search for the end of the unfinished token at `pos`
+    /// continues when more code is fed. The code before it was already searched.
+    resume: usize,
+
+    /// Whether all the code is already given. Otherwise tokens that reach the end of the
+    /// given code are not read until more code is fed.
+    complete: bool,
+
+    /// Decoder of the code that is fed as bytes.
+    decoder: Decoder,
+
+    /// Name of the raw text element which content is being read.
+    raw_text: Option<String>,
+
+    /// Whether the next tags are in foreign content like SVG where there are no raw text
+    /// elements.
+    foreign: bool,
+
+    /// Position of the next token.
+    cursor: Position,
+
+    /// Problems in the code that were found and recovered from.
+    diagnostics: Vec<Error>,
+}
+
+impl<'a> Tokenizer<'a> {
+
+    /// Tokenizer for the complete code.
+    pub fn new(html: &'a str) -> Self {
+        Tokenizer {
+            html: Cow::Borrowed(html),
+            pos: 0,
+            token_start: 0,
+            resume: 0,
+            complete: true,
+            decoder: UTF_8.new_decoder(),
+            raw_text: None,
+            foreign: false,
+            cursor: Position::code_start(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Tokenizer for the code that is going to be fed in parts.
+    pub fn incremental() -> Self {
+        Tokenizer {
+            html: Cow::Owned(String::new()),
+            complete: false,
+            ..Tokenizer::new("")
+        }
+    }
+
+    /// Read the code as a content of the element with given name. Content of raw text
+    /// elements is not parsed as HTML.
+    pub fn enter_element(&mut self, name: &str) {
+        if is_one_of(name, RAW_TEXT_ELEMENTS) {
+            self.raw_text = Some(name.to_ascii_lowercase());
+        }
+    }
+
+    /// Set whether the next tags are in foreign content like SVG. Content of `<style>` or
+    /// `<title>` is parsed as markup there.
+    pub fn set_foreign(&mut self, foreign: bool) {
+        self.foreign = foreign;
+    }
+
+    /// Add the next part of the code in UTF-8. Characters can be split between the parts.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.decode(bytes, false);
+    }
+
+    /// Mark that all the code was fed so the rest of the code can be read.
+    pub fn end_input(&mut self) {
+        self.decode(&[], true);
+        self.complete = true;
+    }
+
+    fn decode(&mut self, mut bytes: &[u8], last: bool) {
+        // Read code is not needed anymore.
+        let html = self.html.to_mut();
+        html.drain(..self.pos);
+        self.resume = self.resume.saturating_sub(self.pos);
+        self.pos = 0;
+        self.token_start = 0;
+
+        loop {
+            let len = self.decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len());
+            html.reserve(len);
+            let (result, read, _) = self.decoder.decode_to_string(bytes, html, last);
+            bytes = &bytes[read..];
+            if let CoderResult::InputEmpty = result {
+                break;
+            }
+        }
+    }
+
+    /// Remember the problem found in the tokens that were already read.
+    pub fn add_diagnostic(&mut self, error: Error) {
+        self.diagnostics.push(error);
+    }
+
+    /// Take the problems that were found in the code read so far.
+    pub fn take_diagnostics(&mut self) -> Vec<Error> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Code of the last read token. If no token was read, this is the code that was dropped
+    /// while trying to read it, e.g. unfinished tag at the end of the code.
+    pub fn source(&self) -> &str {
+        &self.html[self.token_start..self.pos]
+    }
+
+    /// Read next token with the part of the code it occupies. None is returned at the end
+    /// of the code.
+    pub fn next_token(&mut self) -> Option<(Token, Span)> {
+        let from = self.pos;
+        self.token_start = from;
+        let token = self.read_token();
+        if token.is_some() {
+            self.resume = 0;
+        }
+        let start = self.cursor;
+        self.cursor = start.advanced(&self.html[from..self.pos]);
+        token.map(|token| (token, Span::new(start, self.cursor)))
+    }
+
+    /// Read next token. None is returned if the token is not complete yet. Position of the
+    /// tokenizer does not change in this case.
+    fn read_token(&mut self) -> Option<Token> {
+        if self.pos >= self.html.len() {
+            return None;
+        }
+
+        if let Some(name) = self.raw_text.take() {
+            let escapable = is_one_of(&name, ESCAPABLE_RAW_TEXT_ELEMENTS);
+            if !escapable && self.html[self.pos..].starts_with("<![CDATA[") {
+                // CDATA section is kept in scripts and styles as in XHTML.
+                let cdata = self.read_until(9, "]]>", "CDATA").map(Token::CData);
+                self.raw_text = Some(name);
+                return cdata;
+            }
+
+            let end = self.find_raw_text_end(&name, !escapable);
+            if end == self.html.len() && !self.complete {
+                self.raw_text = Some(name);
+                return None;
+            } else if end > self.pos {
+                let text = String::from(&self.html[self.pos..end]);
+                self.pos = end;
+                if self.html[end..].starts_with("<![CDATA[") {
+                    self.raw_text = Some(name);
+                }
+                return Some(Token::RawText { text, escapable });
+            }
+        }
+
+        let rest = &self.html[self.pos..];
+        let bytes = rest.as_bytes();
+        if bytes[0] == b'<' && !self.complete && bytes.len() < "<!DOCTYPE".len() {
+            // It is not known yet what kind of markup this is.
+            if bytes.len() == 1 || bytes[1] == b'!' {
+                return None;
+            }
+        }
+        if bytes[0] == b'<' && bytes.len() > 1 {
+            if rest.starts_with("<!--") {
+                return self.read_until(4, "-->", "comment").map(Token::Comment);
+            } else if rest.starts_with("<![CDATA[") {
+                return self.read_until(9, "]]>", "CDATA").map(Token::CData);
+            } else if starts_with_ignore_case(rest, "<!DOCTYPE") {
+                return self.read_until(9, ">", "DOCTYPE").map(Token::Doctype);
+            } else if bytes[1] == b'!' {
+                // Unknown markup is read as a comment till the nearest '>'.
+                let reported = self.diagnostics.len();
+                let s = self.read_until(2, ">", "comment")?;
+                let error = Error::new(ErrorKind::UnexpectedBang, self.cursor);
+                self.diagnostics.insert(reported, error);
+                return Some(Token::Comment(s));
+            } else if bytes[1] == b'?' {
+                let s = self.read_until(2, "?>", "processing instruction")?;
+                let is_declaration = s.starts_with("xml")
+                    && s[3..].starts_with(|c: char| c.is_ascii_whitespace());
+                return if is_declaration {
+                    Some(Token::Declaration(s))
+                } else {
+                    Some(Token::Instruction(s))
+                };
+            } else if bytes[1] == b'/' {
+                return self.read_end_tag();
+            } else if bytes[1].is_ascii_alphabetic() {
+                return self.read_start_tag();
+            }
+        }
+
+        // Text lasts until the next tag. Single '<' that does not start a tag is a text too.
+        let mut end = self.html.len();
+        let mut from = std::cmp::max(self.pos + 1, self.resume) - self.pos;
+        while let Some(i) = memchr::memchr(b'<', &bytes[from..]) {
+            let i = from + i;
+            match bytes.get(i + 1) {
+                Some(b'!') | Some(b'?') | Some(b'/') => (),
+                Some(c) if c.is_ascii_alphabetic() => (),
+                _ => {
+                    from = i + 1;
+                    continue;
+                },
+            }
+            end = self.pos + i;
+            break;
+        }
+        if end == self.html.len() && !self.complete {
+            // Text can continue in the code that is not fed yet.
+            self.resume = self.resume_offset(self.pos + 1, 2);
+            return None;
+        }
+        let text = String::from(&self.html[self.pos..end]);
+        self.pos = end;
+        Some(Token::Text(text))
+    }
+
+    /// Remember the problem found in the token that is being read.
+    fn report(&mut self, kind: ErrorKind) {
+        self.diagnostics.push(Error::new(kind, self.cursor));
+    }
+
+    /// Read the construct which content starts after `skip` bytes and ends with `end` string.
+    /// If the end is missing then the construct lasts till the end of the code.
+    fn read_until(&mut self, skip: usize, end: &str, what: &str) -> Option<String> {
+        let start = self.pos + skip;
+        let from = std::cmp::max(start, self.resume);
+        match self.html[from..].find(end) {
+            Some(i) => {
+                self.pos = from + i + end.len();
+                Some(String::from(&self.html[start..from + i]))
+            },
+            None if !self.complete => {
+                self.resume = self.resume_offset(start, end.len());
+                None
+            },
+            None => {
+                self.report(ErrorKind::UnexpectedEof(String::from(what)));
+                self.pos = self.html.len();
+                Some(String::from(&self.html[start..]))
+            },
+        }
+    }
+
+    /// Find where the content of raw text element with given name ends. Text also ends
+    /// before CDATA section if `cdata` is set.
+    fn find_raw_text_end(&mut self, name: &str, cdata: bool) -> usize {
+        let bytes = self.html.as_bytes();
+        let mut from = std::cmp::max(self.pos, self.resume);
+        while let Some(i) = memchr::memchr(b'<', &bytes[from..]) {
+            let i = from + i;
+            let rest = &self.html[i..];
+            if cdata && rest.starts_with("<![CDATA[") {
+                return i;
+            } else if rest.starts_with("</") && starts_with_ignore_case(&rest[2..], name) {
+                let after = rest.as_bytes().get(2 + name.len());
+                match after {
+                    None if !self.complete => break,
+                    None | Some(b'>') | Some(b'/') => return i,
+                    Some(c) if c.is_ascii_whitespace() => return i,
+                    _ => (),
+                }
+            }
+            from = i + 1;
+        }
+        // Closing tag or CDATA section can start in the last bytes.
+        self.resume = self.resume_offset(self.pos, std::cmp::max(name.len() + 3, 9));
+        self.html.len()
+    }
+
+    /// Offset the search for the construct of given length continues from when more code is
+    /// fed. The construct can start in the last bytes of the code but not before `from`.
+    fn resume_offset(&self, from: usize, len: usize) -> usize {
+        let mut offset = std::cmp::max(from, self.html.len().saturating_sub(len - 1));
+        while !self.html.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+
+    /// Read the name of the tag or attribute that starts at current position.
+    fn read_name(&mut self, attribute: bool) -> String {
+        let rest = &self.html[self.pos..];
+        let len = rest.find(|c: char| {
+            c.is_ascii_whitespace() || c == '>' || c == '/' || (attribute && c == '=')
+        }).unwrap_or(rest.len());
+        // Attribute name can start with '=' which is a part of the name then.
+        let len = if attribute && len == 0 && rest.starts_with('=') { 1 } else { len };
+        self.pos += len;
+        String::from(&rest[..len])
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.html[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_whitespace()).len();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.html.as_bytes().get(self.pos).cloned()
+    }
+
+    /// Read the opening tag. Unfinished tag at the end of the code is dropped.
+    fn read_start_tag(&mut self) -> Option<Token> {
+        let start = self.pos;
+        self.pos += 1;
+        let name = self.read_name(false);
+
+        let mut attrs = Vec::new();
+        let mut self_closing = false;
+        let mut attr_start = self.pos;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None if !self.complete => {
+                    self.pos = start;
+                    return None;
+                },
+                None => {
+                    self.pos = start;
+                    self.report(ErrorKind::UnexpectedEof(String::from("tag")));
+                    self.pos = self.html.len();
+                    return None;
+                },
+                Some(b'>') => {
+                    self.pos += 1;
+                    break;
+                },
+                Some(b'/') => {
+                    self.pos += 1;
+                    if self.peek() == Some(b'>') {
+                        self.pos += 1;
+                        self_closing = true;
+                        break;
+                    }
+                },
+                Some(_) => {
+                    let mut attr = self.read_attribute();
+                    attr.source = attr_start - start..self.pos - start;
+                    attr_start = self.pos;
+                    attrs.push(attr);
+                },
+            }
+        }
+
+        if !self_closing && !self.foreign && is_one_of(&name, RAW_TEXT_ELEMENTS) {
+            self.raw_text = Some(name.to_ascii_lowercase());
+        }
+
+        Some(Token::StartTag {
+            name,
+            attrs,
+            self_closing,
+        })
+    }
+
+    fn read_attribute(&mut self) -> TokenAttribute {
+        let name = self.read_name(true);
+
+        self.skip_whitespace();
+        if self.peek() != Some(b'=') {
+            return TokenAttribute { name, value: None, source: 0..0 };
+        }
+        self.pos += 1;
+        self.skip_whitespace();
+
+        let rest = &self.html[self.pos..];
+        let value = match self.peek() {
+            Some(quote @ b'"') | Some(quote @ b'\'') => {
+                let end = memchr::memchr(quote, &rest.as_bytes()[1..]);
+                let end = end.map(|i| i + 1).unwrap_or(rest.len());
+                self.pos += std::cmp::min(end + 1, rest.len());
+                &rest[1..end]
+            },
+            _ => {
+                let end = rest.find(|c: char| c.is_ascii_whitespace() || c == '>')
+                    .unwrap_or(rest.len());
+                self.pos += end;
+                &rest[..end]
+            },
+        };
+
+        TokenAttribute {
+            name,
+            value: Some(String::from(value)),
+            source: 0..0,
+        }
+    }
+
+    /// Read the closing tag. Unfinished tag at the end of the code is dropped.
+    fn read_end_tag(&mut self) -> Option<Token> {
+        let start = self.pos;
+        self.pos += 2;
+        let name = self.read_name(false);
+
+        // Anything else in the closing tag is ignored.
+        match memchr::memchr(b'>', &self.html.as_bytes()[self.pos..]) {
+            Some(i) => {
+                self.pos += i + 1;
+                Some(Token::EndTag(name))
+            },
+            None if !self.complete => {
+                self.pos = start;
+                None
+            },
+            None => {
+                self.pos = start;
+                self.report(ErrorKind::UnexpectedEof(String::from("closing tag")));
+                self.pos = self.html.len();
+                None
+            },
+        }
+    }
+}
+
+/// Whether the content of the element with given name is not escaped in any way.
+pub fn is_raw_text_element(name: &str) -> bool {
+    is_one_of(name, RAW_TEXT_ELEMENTS) && !is_one_of(name, ESCAPABLE_RAW_TEXT_ELEMENTS)
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.len() >= prefix.len() && s.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+}